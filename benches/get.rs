@@ -0,0 +1,30 @@
+#[macro_use]
+extern crate criterion;
+extern crate poison;
+
+use criterion::{black_box, Criterion};
+use poison::Poison;
+
+fn bench_get(c: &mut Criterion) {
+    let healthy = Poison::new(1u64);
+    let poisoned = Poison::poisoned(1u64);
+
+    c.bench_function("get healthy", |b| b.iter(|| black_box(&healthy).get().is_ok()));
+    c.bench_function("get poisoned", |b| b.iter(|| black_box(&poisoned).get().is_ok()));
+    c.bench_function("checked_get healthy", |b| b.iter(|| black_box(&healthy).checked_get().is_ok()));
+}
+
+fn bench_lock(c: &mut Criterion) {
+    let mut healthy = Poison::new(1u64);
+    let mut poisoned = Poison::poisoned(1u64);
+
+    c.bench_function("lock healthy", |b| b.iter(|| {
+        *black_box(&mut healthy).lock().unwrap().get_mut() += 1;
+    }));
+    c.bench_function("lock poisoned", |b| b.iter(|| {
+        black_box(&mut poisoned).lock().is_err()
+    }));
+}
+
+criterion_group!(benches, bench_get, bench_lock);
+criterion_main!(benches);
@@ -0,0 +1,728 @@
+//! Atomic poisoning primitives, which can be shared between threads.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LockResult, PoisonError, Weak};
+use std::thread;
+
+/// A raw poisoning primitive backed by a shared atomic flag.
+///
+/// Unlike RawPoison, an AtomicRawPoison can be poisoned, healed and locked
+/// through a shared reference, and can hand out tokens which poison it from
+/// other threads.
+#[derive(Debug)]
+pub struct AtomicRawPoison {
+    flag: Arc<Flag>
+}
+
+/// A poisonable value whose poison state can be shared between threads.
+///
+/// The data can be read concurrently through get, and the poison state
+/// observed, poisoned and healed through shared references, without an outer
+/// Mutex. Poison state changes are published with release and observed with
+/// acquire ordering, so a thread which observes a Poison being healed also
+/// observes writes made before it was healed.
+///
+/// Mutating the data still requires external synchronization, or interior
+/// mutability with its own synchronization. For exclusive access without an
+/// outer Mutex, see SpinPoison.
+#[derive(Debug)]
+pub struct AtomicPoison<T: ?Sized> {
+    raw: AtomicRawPoison,
+    data: T
+}
+
+/// A poisonable value with its own spinlock, for exclusive access from any
+/// thread without an outer Mutex.
+///
+/// Unlike AtomicPoison, the data can only be accessed through try_lock, which
+/// fails instead of waiting if another guard is live. Since at most one guard
+/// is live at a time, and the data is only reachable through it, the guard can
+/// hand out a mutable reference. As with Mutex, a SpinPoison is Sync whenever
+/// the data is Send, since every access to the data is exclusive, so the data
+/// is only ever moved between threads, never shared.
+pub struct SpinPoison<T: ?Sized> {
+    raw: AtomicRawPoison,
+    locked: AtomicBool,
+    data: UnsafeCell<T>
+}
+
+// The data is only ever accessed by the single thread holding the lock.
+unsafe impl<T: ?Sized + Send> Send for SpinPoison<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinPoison<T> {}
+
+/// A guard on a SpinPoison, holding its lock.
+///
+/// If the current thread panics before this instance is dropped, the
+/// SpinPoison will become poisoned when this instance drops, before the lock
+/// is released.
+pub struct SpinPoisonGuard<'poison, T: ?Sized + 'poison> {
+    poison: &'poison SpinPoison<T>,
+    panicking: bool,
+    // Only Sync if the data is, since the guard hands out references to it.
+    _marker: PhantomData<&'poison mut T>
+}
+
+/// A node in a tree of fault domains, where poisoning a node also poisons its
+/// ancestors.
+///
+/// Each node has its own atomic poison flag. Children are created with child
+/// and hold a strong link to their parent, so a node's ancestors live at least
+/// as long as it does. Links are fixed when a node is created and only ever
+/// point towards the root, so the tree cannot contain cycles.
+///
+/// Poisoning a node poisons it and then each of its ancestors in turn, from
+/// the node up to the root, each with release ordering, so a thread which
+/// observes an ancestor poisoned also observes every node below it on that
+/// path poisoned. Poisoning and healing a parent does not affect its children,
+/// and healing a node does not heal its ancestors.
+#[derive(Debug)]
+pub struct PoisonNode {
+    node: Arc<Node>
+}
+
+// The state of a PoisonNode, shared with its children.
+#[derive(Debug)]
+struct Node {
+    raw: AtomicRawPoison,
+    parent: Option<Arc<Node>>
+}
+
+/// A guard on an AtomicRawPoison.
+///
+/// If the current thread panics before this instance is dropped, the
+/// AtomicRawPoison will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct AtomicRawPoisonGuard<'poison> {
+    poison: &'poison AtomicRawPoison,
+    panicking: bool
+}
+
+/// A one-shot token which poisons the AtomicRawPoison it was created from.
+///
+/// Tokens are Send, so they can be handed to a watchdog or timeout thread.
+/// Triggering consumes the token, so each token can poison at most once;
+/// healing the AtomicRawPoison does not invalidate tokens which have not yet
+/// been triggered.
+#[derive(Debug)]
+pub struct PoisonToken {
+    flag: Arc<Flag>
+}
+
+/// A shared handle to the flag of an AtomicRawPoison.
+///
+/// Handles can be used from any thread to observe, poison and heal the
+/// AtomicRawPoison they were created from, and keep the shared flag alive.
+#[derive(Debug, Clone)]
+pub struct PoisonHandle {
+    flag: Arc<Flag>
+}
+
+/// A weak watcher over the flag of an AtomicRawPoison.
+///
+/// Unlike PoisonHandle, a WeakPoison does not keep the flag alive, and can
+/// no longer be upgraded once the AtomicRawPoison it was created from has
+/// been dropped, even if other handles are still alive.
+#[derive(Debug, Clone)]
+pub struct WeakPoison {
+    flag: Weak<Flag>
+}
+
+// The state shared between an AtomicRawPoison and its handles.
+#[derive(Debug)]
+struct Flag {
+    poisoned: AtomicBool,
+    // Whether the owning AtomicRawPoison is still alive.
+    owned: AtomicBool
+}
+
+impl Flag {
+    #[inline]
+    fn new(poisoned: bool) -> Arc<Flag> {
+        Arc::new(Flag {
+            poisoned: AtomicBool::new(poisoned),
+            owned: AtomicBool::new(true)
+        })
+    }
+}
+
+impl AtomicRawPoison {
+    /// Create a new AtomicRawPoison in a non-poisoned state.
+    #[inline]
+    pub fn new() -> AtomicRawPoison {
+        AtomicRawPoison { flag: Flag::new(false) }
+    }
+
+    /// Create a new AtomicRawPoison which is already poisoned.
+    #[inline]
+    pub fn poisoned() -> AtomicRawPoison {
+        AtomicRawPoison { flag: Flag::new(true) }
+    }
+
+    /// Check if the AtomicRawPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.flag.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Poison the AtomicRawPoison.
+    #[inline]
+    pub fn poison(&self) {
+        poison(&self.flag);
+    }
+
+    /// Heal the AtomicRawPoison if it is poisoned.
+    #[inline]
+    pub fn heal(&self) {
+        if self.flag.poisoned.swap(false, Ordering::AcqRel) {
+            healed_transition();
+        }
+    }
+
+    /// Heal the AtomicRawPoison only if its poison state is `current`.
+    ///
+    /// Returns whether the poison state was `current`, in which case the
+    /// AtomicRawPoison is now healed. `compare_and_heal(true)` heals only if
+    /// poisoned, reporting whether this call performed the heal.
+    #[inline]
+    pub fn compare_and_heal(&self, current: bool) -> bool {
+        let healed = self.flag.poisoned
+            .compare_exchange(current, false, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if healed && current { healed_transition(); }
+        healed
+    }
+
+    /// Repeatedly try to heal the AtomicRawPoison, up to `max_attempts` times.
+    ///
+    /// Each attempt heals the AtomicRawPoison if it is poisoned, spinning
+    /// between attempts if another thread re-poisons it concurrently or the
+    /// attempt fails spuriously. Returns whether the AtomicRawPoison was
+    /// observed in a healed state, which includes the case where it was never
+    /// poisoned.
+    pub fn heal_spin(&self, max_attempts: usize) -> bool {
+        for attempt in 0..max_attempts {
+            if attempt > 0 { hint::spin_loop(); }
+
+            match self.flag.poisoned.compare_exchange_weak(true, false, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    healed_transition();
+                    return true;
+                },
+                Err(false) => return true,
+                Err(true) => {}
+            }
+        }
+
+        false
+    }
+
+    /// Get a poison lock on this AtomicRawPoison.
+    ///
+    /// This does not provide mutual exclusion, it only ties poisoning to the
+    /// lifetime of the guard.
+    ///
+    /// If the AtomicRawPoison is already poisoned, returns PoisonError.
+    #[inline]
+    pub fn lock(&self) -> LockResult<AtomicRawPoisonGuard<'_>> {
+        let guard = AtomicRawPoisonGuard {
+            poison: self,
+            panicking: thread::panicking()
+        };
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Create a shared handle to this AtomicRawPoison.
+    #[inline]
+    pub fn handle(&self) -> PoisonHandle {
+        PoisonHandle { flag: self.flag.clone() }
+    }
+
+    /// Get the number of handles sharing this AtomicRawPoison's flag.
+    ///
+    /// Counts every live PoisonHandle and untriggered PoisonToken, but not
+    /// the AtomicRawPoison itself. Useful for detecting observers which
+    /// outlive the structure they watch.
+    #[inline]
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.flag) - 1
+    }
+
+    /// Create a weak watcher over this AtomicRawPoison.
+    #[inline]
+    pub fn weak(&self) -> WeakPoison {
+        WeakPoison { flag: Arc::downgrade(&self.flag) }
+    }
+
+    /// Create a token which poisons this AtomicRawPoison when triggered.
+    #[inline]
+    pub fn poison_token(&self) -> PoisonToken {
+        PoisonToken { flag: self.flag.clone() }
+    }
+}
+
+impl Default for AtomicRawPoison {
+    #[inline]
+    fn default() -> AtomicRawPoison { AtomicRawPoison::new() }
+}
+
+impl Drop for AtomicRawPoison {
+    #[inline]
+    fn drop(&mut self) {
+        self.flag.owned.store(false, Ordering::Release);
+    }
+}
+
+impl WeakPoison {
+    /// Upgrade to a PoisonHandle, if the AtomicRawPoison is still alive.
+    #[inline]
+    pub fn upgrade(&self) -> Option<PoisonHandle> {
+        self.flag.upgrade()
+            .filter(|flag| flag.owned.load(Ordering::Acquire))
+            .map(|flag| PoisonHandle { flag })
+    }
+}
+
+impl PoisonHandle {
+    /// Check if the associated AtomicRawPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.flag.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Poison the associated AtomicRawPoison.
+    #[inline]
+    pub fn poison(&self) {
+        poison(&self.flag);
+    }
+
+    /// Heal the associated AtomicRawPoison if it is poisoned.
+    #[inline]
+    pub fn heal(&self) {
+        if self.flag.poisoned.swap(false, Ordering::AcqRel) {
+            healed_transition();
+        }
+    }
+}
+
+impl PoisonToken {
+    /// Poison the associated AtomicRawPoison, consuming the token.
+    #[inline]
+    pub fn trigger(self) {
+        poison(&self.flag);
+    }
+}
+
+impl<'poison> Drop for AtomicRawPoisonGuard<'poison> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.poison.poison();
+        }
+    }
+}
+
+impl<T> AtomicPoison<T> {
+    /// Create a new AtomicPoison in a non-poisoned state.
+    #[inline]
+    pub fn new(data: T) -> AtomicPoison<T> {
+        AtomicPoison { raw: AtomicRawPoison::new(), data }
+    }
+
+    /// Extract the data from the AtomicPoison.
+    ///
+    /// Returns PoisonError if the AtomicPoison is poisoned.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        if self.raw.is_poisoned() { Err(PoisonError::new(self.data)) } else { Ok(self.data) }
+    }
+}
+
+impl<T: ?Sized> AtomicPoison<T> {
+    /// Get an immutable reference to the data.
+    ///
+    /// Returns PoisonError if the AtomicPoison is poisoned.
+    #[inline]
+    pub fn get(&self) -> LockResult<&T> {
+        if self.raw.is_poisoned() { Err(PoisonError::new(&self.data)) } else { Ok(&self.data) }
+    }
+
+    /// Check if the AtomicPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.is_poisoned()
+    }
+
+    /// Poison the AtomicPoison.
+    #[inline]
+    pub fn poison(&self) {
+        self.raw.poison()
+    }
+
+    /// Heal the AtomicPoison if it is poisoned.
+    #[inline]
+    pub fn heal(&self) {
+        self.raw.heal()
+    }
+
+    /// Poison the AtomicPoison to tell other threads to stop.
+    ///
+    /// For fail-fast pipelines: the flag is set with release ordering, so
+    /// every write this thread made before calling poison_and_notify, such as
+    /// recording the error, happens before any check which returns true.
+    #[inline]
+    pub fn poison_and_notify(&self) {
+        self.raw.poison()
+    }
+
+    /// Check whether another thread has called poison_and_notify.
+    ///
+    /// The flag is read with acquire ordering, so once this returns true, the
+    /// writes made by the poisoning thread before it poisoned are visible.
+    #[inline]
+    pub fn check(&self) -> bool {
+        self.raw.is_poisoned()
+    }
+
+    /// Create a shared handle to this AtomicPoison's poison state.
+    #[inline]
+    pub fn handle(&self) -> PoisonHandle {
+        self.raw.handle()
+    }
+}
+
+impl<T> SpinPoison<T> {
+    /// Create a new SpinPoison in a non-poisoned state.
+    #[inline]
+    pub fn new(data: T) -> SpinPoison<T> {
+        SpinPoison {
+            raw: AtomicRawPoison::new(),
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data)
+        }
+    }
+
+    /// Extract the data from the SpinPoison.
+    ///
+    /// Returns PoisonError if the SpinPoison is poisoned.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        let data = self.data.into_inner();
+        if self.raw.is_poisoned() { Err(PoisonError::new(data)) } else { Ok(data) }
+    }
+}
+
+impl<T: ?Sized> SpinPoison<T> {
+    /// Try to lock this SpinPoison, without waiting.
+    ///
+    /// Returns None if another guard on this SpinPoison is live, or
+    /// PoisonError if the SpinPoison is poisoned.
+    #[inline]
+    pub fn try_lock(&self) -> Option<LockResult<SpinPoisonGuard<'_, T>>> {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None
+        }
+
+        let guard = SpinPoisonGuard { poison: self, panicking: thread::panicking(), _marker: PhantomData };
+        Some(if self.raw.is_poisoned() { Err(PoisonError::new(guard)) } else { Ok(guard) })
+    }
+
+    /// Check if the SpinPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.is_poisoned()
+    }
+
+    /// Heal the SpinPoison if it is poisoned.
+    #[inline]
+    pub fn heal(&self) {
+        self.raw.heal()
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for SpinPoison<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpinPoison")
+            .field("poisoned", &self.raw.is_poisoned())
+            .field("locked", &self.locked.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<'poison, T: ?Sized> SpinPoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T {
+        // The guard holds the lock, so no other reference to the data exists.
+        unsafe { &*self.poison.data.get() }
+    }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.poison.data.get() }
+    }
+}
+
+impl<'poison, T: ?Sized> Drop for SpinPoisonGuard<'poison, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.poison.raw.poison();
+        }
+
+        self.poison.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'poison, T: ?Sized + fmt::Debug> fmt::Debug for SpinPoisonGuard<'poison, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpinPoisonGuard").field("data", &self.get()).finish()
+    }
+}
+
+impl PoisonNode {
+    /// Create a new root PoisonNode in a non-poisoned state.
+    #[inline]
+    pub fn new() -> PoisonNode {
+        PoisonNode { node: Arc::new(Node { raw: AtomicRawPoison::new(), parent: None }) }
+    }
+
+    /// Create a child of this PoisonNode in a non-poisoned state.
+    #[inline]
+    pub fn child(&self) -> PoisonNode {
+        PoisonNode {
+            node: Arc::new(Node { raw: AtomicRawPoison::new(), parent: Some(self.node.clone()) })
+        }
+    }
+
+    /// Check if this PoisonNode is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.node.raw.is_poisoned()
+    }
+
+    /// Poison this PoisonNode and all of its ancestors.
+    #[inline]
+    pub fn poison(&self) {
+        let mut node = Some(&self.node);
+
+        while let Some(current) = node {
+            current.raw.poison();
+            node = current.parent.as_ref();
+        }
+    }
+
+    /// Heal this PoisonNode if it is poisoned, leaving its ancestors as they are.
+    #[inline]
+    pub fn heal(&self) {
+        self.node.raw.heal()
+    }
+}
+
+impl Default for PoisonNode {
+    #[inline]
+    fn default() -> PoisonNode { PoisonNode::new() }
+}
+
+// Record that the flag was healed.
+#[inline]
+fn healed_transition() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("poison.healed").increment(1);
+}
+
+// Set the flag, recording the transition.
+#[inline]
+fn poison(flag: &Flag) {
+    if !flag.poisoned.swap(true, Ordering::AcqRel) {
+        #[cfg(feature = "metrics")]
+        ::metrics::counter!("poison.poisoned").increment(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use {AtomicRawPoison, Poison, PoisonNode, SpinPoison};
+
+    #[test]
+    fn test_atomic_raw_poison() {
+        let poison = Arc::new(AtomicRawPoison::new());
+        let shared = poison.clone();
+
+        thread::spawn(move || {
+            let _guard = shared.lock().unwrap();
+            panic!();
+        }).join().unwrap_err();
+
+        poison.lock().unwrap_err();
+        poison.heal();
+        poison.lock().unwrap();
+    }
+
+    #[test]
+    fn test_atomic_poison() {
+        let shared = Poison::shared(vec![1]);
+        let reader = shared.clone();
+
+        thread::spawn(move || {
+            assert_eq!(*reader.get().unwrap(), [1]);
+            reader.poison();
+        }).join().unwrap();
+
+        assert!(shared.get().is_err());
+        shared.heal();
+        assert_eq!(*shared.get().unwrap(), [1]);
+    }
+
+    #[test]
+    fn test_poison_and_notify() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let shared = Poison::shared(AtomicUsize::new(0));
+
+        let workers = (0..4).map(|id| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                if id == 2 {
+                    shared.get().unwrap().store(id, Ordering::Relaxed);
+                    shared.poison_and_notify();
+                }
+
+                while !shared.check() { thread::yield_now(); }
+                shared.get().unwrap_err().into_inner().load(Ordering::Relaxed)
+            })
+        }).collect::<Vec<_>>();
+
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_spin_poison() {
+        let poison = Arc::new(SpinPoison::new(0));
+
+        let workers = (0..4).map(|_| {
+            let poison = poison.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    loop {
+                        if let Some(guard) = poison.try_lock() {
+                            *guard.unwrap().get_mut() += 1;
+                            break
+                        }
+                        thread::yield_now();
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for worker in workers { worker.join().unwrap(); }
+
+        let guard = poison.try_lock().unwrap().unwrap();
+        assert_eq!(*guard.get(), 400);
+        assert!(poison.try_lock().is_none());
+        drop(guard);
+
+        let shared = poison.clone();
+        thread::spawn(move || {
+            let _guard = shared.try_lock().unwrap().unwrap();
+            panic!();
+        }).join().unwrap_err();
+
+        assert!(poison.try_lock().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_poison_node() {
+        let root = PoisonNode::new();
+        let (left, right) = (root.child(), root.child());
+        let leaf = left.child();
+
+        leaf.poison();
+        assert!(leaf.is_poisoned() && left.is_poisoned() && root.is_poisoned());
+        assert!(!right.is_poisoned());
+
+        root.heal();
+        right.poison();
+        assert!(root.is_poisoned());
+        assert!(left.is_poisoned());
+
+        drop(root);
+        left.heal();
+        leaf.poison();
+        assert!(left.is_poisoned());
+    }
+
+    #[test]
+    fn test_poison_token() {
+        let poison = AtomicRawPoison::new();
+        let token = poison.poison_token();
+
+        thread::spawn(move || token.trigger()).join().unwrap();
+
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_compare_and_heal() {
+        let poison = AtomicRawPoison::poisoned();
+        assert!(!poison.compare_and_heal(false));
+        assert!(poison.compare_and_heal(true));
+        assert!(!poison.compare_and_heal(true));
+        assert!(!poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_heal_spin() {
+        let poison = AtomicRawPoison::poisoned();
+        assert!(!poison.heal_spin(0));
+        assert!(poison.heal_spin(8));
+        assert!(!poison.is_poisoned());
+        assert!(poison.heal_spin(1));
+    }
+
+    #[test]
+    fn test_handle() {
+        let poison = AtomicRawPoison::new();
+        assert_eq!(poison.handle_count(), 0);
+
+        let handle = poison.handle();
+        let token = poison.poison_token();
+        assert_eq!(poison.handle_count(), 2);
+
+        token.trigger();
+        assert_eq!(poison.handle_count(), 1);
+        assert!(handle.is_poisoned());
+
+        handle.heal();
+        assert!(!poison.is_poisoned());
+        drop(handle);
+        assert_eq!(poison.handle_count(), 0);
+    }
+
+    #[test]
+    fn test_weak() {
+        let poison = AtomicRawPoison::new();
+        let weak = poison.weak();
+        let handle = poison.handle();
+        assert_eq!(poison.handle_count(), 1);
+
+        weak.upgrade().unwrap().poison();
+        assert!(poison.is_poisoned());
+
+        drop(poison);
+        assert!(weak.upgrade().is_none());
+        assert!(handle.is_poisoned());
+    }
+}
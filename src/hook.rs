@@ -0,0 +1,180 @@
+//! Thread-local tracking of live guards, and panic hook integration for
+//! capturing the backtraces of poisoning panics.
+//!
+//! Every RawPoisonGuard registers itself here when created and deregisters
+//! when dropped, so the number of live guards on the current thread is always
+//! known. A guard which is leaked, for instance with `mem::forget`, never
+//! deregisters, so it is counted as live for the rest of the thread's life.
+//! The count cannot tell a leaked guard from a live one, so it is only used
+//! for diagnostics and for deciding when to capture backtraces, never to make
+//! a Poison refuse to lock.
+//!
+//! With the `backtrace` feature, a panic hook is installed while any thread
+//! has a live guard, chaining to whichever hook was installed before it, and
+//! the previous hook is restored once the last guard in the process drops.
+//! While at least one guard is live on a thread, panics on that thread capture
+//! a backtrace which the guard picks up when it poisons during unwinding.
+//!
+//! Whenever the outermost guard on a thread is created, the hook is checked
+//! and reinstalled on top of any hook set with `std::panic::set_hook` in the
+//! meantime, so such a hook only stops backtraces from being captured for
+//! guards which are already live. Hooks cannot be changed while panicking,
+//! so if the last guard drops during unwinding, or a guard is leaked, the hook
+//! stays installed until the next time the last guard drops normally.
+
+use std::cell::Cell;
+
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "backtrace")]
+use std::cell::RefCell;
+#[cfg(feature = "backtrace")]
+use std::panic::{self, PanicHookInfo};
+#[cfg(feature = "backtrace")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "backtrace")]
+use std::thread;
+
+#[cfg(feature = "backtrace")]
+type Hook = Box<dyn Fn(&PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+// The state of the installed hook, shared by all threads.
+#[cfg(feature = "backtrace")]
+struct HookState {
+    // The number of threads with at least one live guard.
+    scopes: usize,
+    // The address of the installed hook, used to tell whether it is still
+    // the current one.
+    installed: Option<usize>,
+    // The hook which was current when ours was installed.
+    previous: Option<Arc<Hook>>
+}
+
+#[cfg(feature = "backtrace")]
+static HOOK: Mutex<HookState> = Mutex::new(HookState { scopes: 0, installed: None, previous: None });
+
+thread_local! {
+    // The number of live guards on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(feature = "backtrace")]
+thread_local! {
+    // The backtrace of the last panic on this thread while a guard was live.
+    static CAPTURED: RefCell<Option<Arc<Backtrace>>> = const { RefCell::new(None) };
+    // Whether this thread is counted in the scopes of the hook.
+    static SCOPED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Record that a guard has been created on this thread.
+#[inline]
+pub fn enter() {
+    let _ = DEPTH.try_with(|depth| {
+        depth.set(depth.get() + 1);
+
+        #[cfg(feature = "backtrace")]
+        {
+            if depth.get() == 1 { open_scope(); }
+        }
+    });
+}
+
+/// Record that a guard has been dropped on this thread.
+///
+/// Once the last guard is dropped, any captured backtrace is discarded.
+#[inline]
+pub fn exit() {
+    let _ = DEPTH.try_with(|depth| {
+        let remaining = depth.get().saturating_sub(1);
+        depth.set(remaining);
+
+        #[cfg(feature = "backtrace")]
+        {
+            if remaining == 0 {
+                let _ = CAPTURED.try_with(|captured| captured.borrow_mut().take());
+                close_scope();
+            }
+        }
+    });
+}
+
+/// Get the number of live guards on this thread.
+#[inline]
+pub fn depth() -> usize {
+    DEPTH.try_with(|depth| depth.get()).unwrap_or(0)
+}
+
+/// Get the backtrace of the panic currently unwinding through a guard, if any.
+#[cfg(feature = "backtrace")]
+pub fn current() -> Option<Arc<Backtrace>> {
+    CAPTURED.try_with(|captured| captured.borrow().clone()).ok().flatten()
+}
+
+#[cfg(feature = "backtrace")]
+fn address(hook: &Hook) -> usize {
+    &**hook as *const _ as *const () as usize
+}
+
+// The first guard on this thread was created: make sure the hook is current.
+#[cfg(feature = "backtrace")]
+fn open_scope() {
+    // The hook cannot be swapped while panicking, and any panic in progress
+    // would not be captured anyway. Not taking the lock also means a guard
+    // created inside a panic hook cannot deadlock with another thread.
+    if thread::panicking() { return }
+
+    let mut state = HOOK.lock().unwrap_or_else(|err| err.into_inner());
+    state.scopes += 1;
+    let _ = SCOPED.try_with(|scoped| scoped.set(true));
+
+    let current = panic::take_hook();
+    if state.installed == Some(address(&current)) {
+        panic::set_hook(current);
+        return
+    }
+
+    let previous = Arc::new(current);
+    let chained = previous.clone();
+    let hook: Hook = Box::new(move |info| {
+        if depth() > 0 {
+            let backtrace = Arc::new(Backtrace::force_capture());
+            let _ = CAPTURED.try_with(|captured| *captured.borrow_mut() = Some(backtrace));
+        }
+
+        chained(info);
+    });
+
+    state.installed = Some(address(&hook));
+    state.previous = Some(previous);
+    panic::set_hook(hook);
+}
+
+// The last guard on this thread was dropped: restore the previous hook if no
+// other thread has a live guard.
+#[cfg(feature = "backtrace")]
+fn close_scope() {
+    if !SCOPED.try_with(|scoped| scoped.replace(false)).unwrap_or(false) { return }
+
+    let mut state = HOOK.lock().unwrap_or_else(|err| err.into_inner());
+    state.scopes = state.scopes.saturating_sub(1);
+
+    if state.scopes > 0 || state.installed.is_none() || thread::panicking() { return }
+
+    let current = panic::take_hook();
+    let previous = state.previous.take();
+    let ours = state.installed.take() == Some(address(&current));
+
+    match previous {
+        // Dropping our hook releases its reference to the previous hook, so
+        // the original can usually be put back as it was.
+        Some(previous) if ours => {
+            drop(current);
+            match Arc::try_unwrap(previous) {
+                Ok(previous) => panic::set_hook(previous),
+                Err(previous) => panic::set_hook(Box::new(move |info| previous(info)))
+            }
+        },
+        // A hook set since ours was installed stays current.
+        _ => panic::set_hook(current)
+    }
+}
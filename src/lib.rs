@@ -1,12 +1,94 @@
 #![cfg_attr(test, deny(warnings))]
 #![deny(missing_docs)]
+#![cfg_attr(feature = "nightly", feature(coerce_unsized, unsize))]
 
 //! # poison
 //!
 //! Provides ergonomic poisoning primitives for building poisonable structures.
+//!
+//! ## Features
+//!
+//! - `metrics`: emit `poison.poisoned` and `poison.healed` counters through
+//!   the `metrics` facade whenever a poison fires or heals, and
+//!   `poison.observed` when an observe-only poison would have fired.
+//! - `tracing`: open a `tracing` span for each guard, recording where it was
+//!   locked, and emit events whenever a poison fires or heals.
+//! - `backtrace`: capture a backtrace of the panic which poisoned a structure,
+//!   available through `poison_backtrace`. A panic hook is installed while
+//!   guards are live, chaining to the previous hook, and removed afterwards.
+//! - `reason`: record the message of the panic which poisoned a structure
+//!   in scope_capturing, available through `poison_reason` and
+//!   `poison_reasons`.
+//! - `timestamp`: record when a structure was poisoned, available through
+//!   `poisoned_since`.
+//! - `audit`: keep a bounded history of recent poison state transitions,
+//!   available through `history`.
+//! - `async`: add `with_async`, which holds a poison lock across an async
+//!   operation and poisons if it is cancelled before completing.
+//! - `reentrancy-check`: in debug builds, panic when a guard is created on a
+//!   Poison which already has a live guard, which means either that two
+//!   guards alias through unsafe code, or that a guard was leaked, for instance
+//!   with `mem::forget`. Opt-in, since leaking a guard is safe.
+//! - `test-util`: add `RawPoisonGuard::force_poison_on_drop`, for testing
+//!   the poisoning path without real panics, and `seed_sampling`, for making
+//!   sampled checks deterministic.
+//! - `nightly`: implement `CoerceUnsized` for `Poison` and `PoisonGuard`.
+//!   Requires a nightly compiler.
+//!
+//! ## Unsizing
+//!
+//! Poison supports unsized data, so pointers to a Poison can be coerced
+//! like pointers to the data itself, for instance from `Box<Poison<[T; N]>>`
+//! to `Box<Poison<[T]>>` or from `&mut Poison<Concrete>` to
+//! `&mut Poison<dyn Trait>`.
 
-use std::sync::{PoisonError, LockResult};
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+use std::any::Any;
+use std::array;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::panic::{self, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, LockResult};
+use std::sync::atomic::AtomicBool;
+use std::ptr;
 use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "nightly")]
+use std::marker::Unsize;
+#[cfg(feature = "nightly")]
+use std::ops::CoerceUnsized;
+
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "tracing")]
+use std::panic::Location;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+
+pub use atomic::{AtomicPoison, AtomicRawPoison, AtomicRawPoisonGuard, PoisonHandle, PoisonNode,
+                 PoisonToken, SpinPoison, SpinPoisonGuard, WeakPoison};
+pub use reentrant::{ReentrantPoison, ReentrantPoisonGuard};
+
+mod atomic;
+mod hook;
+mod reentrant;
+mod sample;
 
 /// A typed poisoning wrapper.
 ///
@@ -14,6 +96,7 @@ use std::thread;
 #[derive(Debug)]
 pub struct Poison<T: ?Sized> {
     raw: RawPoison,
+    // Must be the last field to support unsizing.
     data: T
 }
 
@@ -21,12 +104,18 @@ pub struct Poison<T: ?Sized> {
 ///
 /// If the current thread panics before this instance is dropped, the
 /// Poision will become poisoned when this instance drops.
-#[derive(Debug)]
 pub struct PoisonGuard<'poison, T: ?Sized + 'poison> {
     data: &'poison mut T,
-    guard: RawPoisonGuard<'poison>
+    guard: RawPoisonGuard<'poison>,
+    // Takes a type-erased pointer to the data, so that unsizing the guard
+    // does not change the type of this field.
+    deferred: Option<Deferred<'poison>>
 }
 
+// A closure deferred by PoisonGuard::defer. Send, and wrapped in Callback for
+// Sync, so that registering one does not change the auto traits of the guard.
+type Deferred<'poison> = Callback<dyn FnOnce(*mut ()) + Send + 'poison>;
+
 impl<T> Poison<T> {
     /// Create a new Poison in the non-poisoned state.
     #[inline]
@@ -37,6 +126,16 @@ impl<T> Poison<T> {
         }
     }
 
+    /// Create a new Poison in observe-only mode.
+    ///
+    /// See RawPoison::set_observe_only.
+    #[inline]
+    pub fn new_observing(val: T) -> Self {
+        let mut poison = Poison::new(val);
+        poison.set_observe_only(true);
+        poison
+    }
+
     /// Create a new Poison that is already poisoned.
     #[inline]
     pub fn poisoned(val: T) -> Self {
@@ -57,184 +156,4820 @@ impl<T> Poison<T> {
             Ok(self.data)
         }
     }
-}
 
-impl<T: ?Sized> Poison<T> {
-    /// Get a poison lock on this poison.
+    /// Extract the data if the Poison is not poisoned.
     ///
-    /// Returns PoisonError if the Poison is poisoned.
+    /// Returns None, discarding the data, if the Poison is poisoned.
     #[inline]
-    pub fn lock(&mut self) -> LockResult<PoisonGuard<T>> {
-        let data = &mut self.data;
-        map_result(self.raw.lock(), move |lock| PoisonGuard { data: data, guard: lock })
+    pub fn ok(self) -> Option<T> {
+        if self.raw.poisoned { None } else { Some(self.data) }
     }
 
-    /// Heal the Poison, unpoisoning it if it is poisoned.
+    /// Extract the data only if the Poison is poisoned.
+    ///
+    /// Returns None, discarding the data, if the Poison is not poisoned.
     #[inline]
-    pub fn heal(&mut self) {
+    pub fn err(self) -> Option<T> {
+        if self.raw.poisoned { Some(self.data) } else { None }
+    }
+
+    /// Apply a function to the data, or return a default if poisoned.
+    #[inline]
+    pub fn map_or<U, F: FnOnce(T) -> U>(self, default: U, f: F) -> U {
+        if self.raw.poisoned { default } else { f(self.data) }
+    }
+
+    /// Apply a function to the data, or a fallback function if poisoned.
+    ///
+    /// The fallback receives the possibly corrupt data of the poisoned Poison.
+    #[inline]
+    pub fn map_or_else<U, D, F>(self, default: D, f: F) -> U
+                                where D: FnOnce(T) -> U, F: FnOnce(T) -> U {
+        if self.raw.poisoned { default(self.data) } else { f(self.data) }
+    }
+
+    /// Convert the data into another type, keeping the poison state.
+    #[inline]
+    pub fn map_into<U: From<T>>(self) -> Poison<U> {
+        Poison { raw: self.raw, data: U::from(self.data) }
+    }
+
+    /// Split the contained collection at the given index.
+    ///
+    /// The returned Poison holds the tail of the collection and starts out
+    /// non-poisoned. This Poison keeps the head and retains its poison state.
+    #[inline]
+    pub fn split_off(&mut self, at: usize) -> Poison<T::Output> where T: SplitOff {
+        Poison::new(self.data.split_off(at))
+    }
+
+    /// Shrink the capacity of the contained collection as much as possible.
+    ///
+    /// This deliberately bypasses the poison check, since capacity management
+    /// cannot leave the data in an invalid state. The poison state is left
+    /// unchanged.
+    #[inline]
+    pub fn shrink_to_fit(&mut self) where T: ShrinkToFit {
+        self.data.shrink_to_fit()
+    }
+
+    /// Overwrite the data with known-good data and heal the Poison.
+    ///
+    /// The old data is dropped. Unlike writing through a guard from lock,
+    /// this works regardless of the poison state and always leaves the Poison
+    /// healthy.
+    #[inline]
+    pub fn set(&mut self, val: T) {
+        self.data = val;
         self.raw.heal();
     }
 
-    /// Get an immutable reference to the data in this poison.
+    /// Replace the data with `val` and heal, but only if the Poison is poisoned.
     ///
-    /// There is no guard for an immutable reference, since the data must either
-    /// be immutable or internally poisoned if it has interior mutability.
+    /// Returns the old, possibly corrupt, data if it was replaced. If the
+    /// Poison is healthy, `val` is dropped and None is returned.
     #[inline]
-    pub fn get(&self) -> LockResult<&T> {
+    pub fn replace_if_poisoned(&mut self, val: T) -> Option<T> {
+        if !self.raw.poisoned { return None }
+
+        let old = mem::replace(&mut self.data, val);
+        self.raw.heal();
+        Some(old)
+    }
+
+    /// Replace the data with `new` if it equals `expected`.
+    ///
+    /// Returns whether the data was replaced. The comparison and replacement
+    /// run under a guard, so a panic in either poisons the Poison.
+    ///
+    /// Returns PoisonError containing false, without comparing or replacing
+    /// the data, if the Poison is poisoned.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn compare_and_set(&mut self, expected: &T, new: T) -> LockResult<bool>
+                           where T: PartialEq {
+        match self.lock() {
+            Ok(mut guard) => {
+                let data = guard.get_mut();
+                if *data == *expected {
+                    *data = new;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            },
+            Err(_) => Err(PoisonError::new(false))
+        }
+    }
+
+    /// Get a guard, rebuilding the data from scratch if poisoned.
+    ///
+    /// If the Poison is poisoned, the current data is discarded and replaced
+    /// with the result of `factory`, and the Poison is healed before the guard
+    /// is returned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn recover_with<F: FnOnce() -> T>(&mut self, factory: F) -> PoisonGuard<'_, T> {
         if self.raw.poisoned {
-            Err(PoisonError::new(&self.data))
-        } else {
-            Ok(&self.data)
+            self.data = factory();
+            self.raw.heal();
         }
+
+        PoisonGuard { data: &mut self.data, guard: RawPoisonGuard::new(&mut self.raw, PoisonMode::OnPanic), deferred: None }
     }
 
-    /// Get a mutable reference without a guard.
+    /// Clone the Poison, respecting poisoning.
     ///
-    /// Should only be used in combination with PoisonGuard::into_raw.
-    pub unsafe fn get_mut(&mut self) -> &mut T { &mut self.data }
-}
+    /// Returns PoisonError containing a poisoned clone if this Poison is
+    /// poisoned, so cloning corrupt data must be explicitly acknowledged.
+    #[inline]
+    pub fn try_clone(&self) -> LockResult<Poison<T>> where T: Clone {
+        if self.raw.poisoned {
+            Err(PoisonError::new(Poison::poisoned(self.data.clone())))
+        } else {
+            Ok(Poison::new(self.data.clone()))
+        }
+    }
 
-impl<'poison, T: ?Sized> PoisonGuard<'poison, T> {
-    /// Get an immutable reference to the data.
-    pub fn get(&self) -> &T { &self.data }
+    /// Get a view of the data which is borrowed if healthy and owned if poisoned.
+    ///
+    /// Healthy data is borrowed, since it is safe to read in place. Poisoned
+    /// data is cloned, so that callers do not hold a reference into a
+    /// structure which is likely to be repaired or replaced.
+    #[inline]
+    pub fn to_cow(&self) -> Cow<'_, T> where T: Clone {
+        if self.raw.poisoned { Cow::Owned(self.data.clone()) } else { Cow::Borrowed(&self.data) }
+    }
 
-    /// Get a mutable reference to the data.
-    pub fn get_mut(&mut self) -> &mut T { &mut self.data }
+    /// Capture a copy of the data and poison state, to be restored later.
+    #[inline]
+    pub fn snapshot(&self) -> PoisonSnapshot<T> where T: Clone {
+        PoisonSnapshot { data: self.data.clone(), poisoned: self.raw.poisoned }
+    }
 
-    /// Get a reference that escapes the guard.
+    /// Restore the data and poison state from a snapshot.
     ///
-    /// Should only be used if the data will be externally poisoned.
-    pub unsafe fn into_mut(self) -> &'poison mut T { self.data }
+    /// Unlike heal, this also undoes any changes made to the data since the
+    /// snapshot was taken.
+    #[inline]
+    pub fn restore(&mut self, snap: PoisonSnapshot<T>) {
+        self.data = snap.data;
 
-    /// Get the raw poison guard.
-    pub fn into_raw(self) -> RawPoisonGuard<'poison> { self.guard }
-}
+        if snap.poisoned { self.raw.poison() } else { self.raw.heal() }
+    }
 
-/// A raw poisoning primitive, can be used to build automatically poisoning structures.
-#[derive(Debug)]
-pub struct RawPoison {
-    poisoned: bool
-}
+    /// Move the data into an Arc, with a shared handle tracking its poison state.
+    ///
+    /// The handle starts out in this Poison's poison state and can then be
+    /// poisoned and healed independently of the data. The data and handle have
+    /// decoupled lifetimes: dropping every clone of the Arc does not affect
+    /// the handle, and dropping the handle does not affect the data.
+    #[inline]
+    pub fn into_arc_handle(self) -> (Arc<T>, PoisonHandle) {
+        let poison = if self.raw.poisoned { AtomicRawPoison::poisoned() } else { AtomicRawPoison::new() };
+        (Arc::new(self.data), poison.handle())
+    }
 
-/// A guard on a RawPoison.
-///
-/// If the current thread panics before this instance is dropped, the RawPoison
-/// will become poisoned when this instance drops.
-#[derive(Debug)]
-pub struct RawPoisonGuard<'poison> {
-    poison: &'poison mut RawPoison,
-    panicking: bool
-}
+    /// Erase the type of the data, keeping the poison state.
+    ///
+    /// Allows storing Poisons of different types together. The concrete type
+    /// can be recovered with downcast.
+    #[inline]
+    pub fn erase(self) -> Poison<Box<dyn Any + Send>> where T: Any + Send {
+        Poison { raw: self.raw, data: Box::new(self.data) }
+    }
 
-impl RawPoison {
-    /// Create a new RawPoison in a non-poisoned state.
+    /// Wrap the Poison in a Mutex, the recommended way to share it.
+    ///
+    /// The Mutex guards against concurrent access while the Poison tracks
+    /// whether the data was left in an invalid state.
     #[inline]
-    pub fn new() -> RawPoison {
-        RawPoison { poisoned: false }
+    pub fn into_mutex(self) -> Mutex<Poison<T>> {
+        Mutex::new(self)
     }
 
-    /// Create a new RawPoison which is already poisoned.
+    /// Extract a Poison from a Mutex created with into_mutex.
+    ///
+    /// Returns PoisonError if the Mutex itself is poisoned.
     #[inline]
-    pub fn poisoned() -> RawPoison {
-        RawPoison { poisoned: true }
+    pub fn from_mutex(mutex: Mutex<Poison<T>>) -> LockResult<Poison<T>> {
+        mutex.into_inner()
     }
 
-    /// Heal the RawPoison if it is poisoned.
+    /// Create a new shared Poison in the canonical `Arc<Mutex<Poison<T>>>` stack.
     #[inline]
-    pub fn heal(&mut self) {
-        self.poisoned = false;
+    pub fn arc_mutex(val: T) -> Arc<Mutex<Poison<T>>> {
+        Arc::new(Mutex::new(Poison::new(val)))
     }
 
-    /// Get a poison lock on this RawPoison.
+    /// Create a new shared AtomicPoison, for read-mostly data.
     ///
-    /// If the RawPoison is already poisoned, returns PoisonError.
+    /// Unlike arc_mutex, there is no outer Mutex: the data can be read and the
+    /// poison state changed concurrently, but mutating the data needs unique
+    /// access or its own synchronization. See AtomicPoison.
     #[inline]
-    pub fn lock(&mut self) -> LockResult<RawPoisonGuard> {
-        let poisoned = self.poisoned;
+    pub fn shared(val: T) -> Arc<AtomicPoison<T>> {
+        Arc::new(AtomicPoison::new(val))
+    }
 
-        let guard = RawPoisonGuard {
-            poison: self,
-            panicking: thread::panicking()
-        };
+    /// Lock both the Mutex and the Poison inside it, returning a combined guard.
+    ///
+    /// Poisoning of the Mutex itself is ignored, since the Poison tracks
+    /// whether the data was left in an invalid state.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    pub fn lock_arc(mutex: &Mutex<Poison<T>>) -> LockResult<PoisonMutexGuard<'_, T>> {
+        let guard = mutex.lock().unwrap_or_else(PoisonError::into_inner);
+        let poisoned = guard.raw.poisoned;
 
-        if poisoned {
-            Err(PoisonError::new(guard))
-        } else {
-            Ok(guard)
-        }
+        hook::enter();
+        let guard = PoisonMutexGuard { guard, panicking: thread::panicking() };
+
+        if poisoned { Err(PoisonError::new(guard)) } else { Ok(guard) }
+    }
+
+    /// Like lock_arc, but the returned guard owns the Arc.
+    ///
+    /// The guard has no lifetime, so it can be stored in structs or moved
+    /// into closures, and keeps the Mutex alive until it is dropped.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    pub fn lock_owned(mutex: Arc<Mutex<Poison<T>>>) -> LockResult<OwnedPoisonGuard<T>>
+                      where T: 'static {
+        // The Mutex lives on the heap behind the Arc, which the guard keeps
+        // alive and drops only after the borrowing guard.
+        let borrowed: &'static Mutex<Poison<T>> = unsafe { &*Arc::as_ptr(&mutex) };
+
+        map_result(Poison::lock_arc(borrowed), move |guard| OwnedPoisonGuard { guard, _mutex: mutex })
     }
 }
 
-impl<'poison> Drop for RawPoisonGuard<'poison> {
+impl<T: ?Sized> Poison<T> {
+    /// Get a poison lock on this poison.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline(always)]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock(&mut self) -> LockResult<PoisonGuard<'_, T>> {
+        let data = &mut self.data;
+        map_result(self.raw.lock(), move |lock| PoisonGuard { data, guard: lock, deferred: None })
+    }
+
+    /// Get a poison lock on this poison only if a condition holds.
+    ///
+    /// `cond` is checked against the data before any guard is created, and
+    /// None is returned if it is false. The check runs regardless of the
+    /// poison state.
     #[inline]
-    fn drop(&mut self) {
-        if !self.panicking && thread::panicking() {
-            self.poison.poisoned = true;
-        }
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_while<F: FnOnce(&T) -> bool>(&mut self, cond: F)
+                                             -> Option<LockResult<PoisonGuard<'_, T>>> {
+        if cond(&self.data) { Some(self.lock()) } else { None }
     }
-}
 
-/// A simple, useful combinator for dealing with LockResult.
-///
-/// Applies the action to either the Ok or Err variants
-/// of the LockResult and returns a new LockResult in the same
-/// state with a new value.
-pub fn map_result<T, U, F>(result: LockResult<T>, f: F)
-                           -> LockResult<U>
-                           where F: FnOnce(T) -> U {
-    match result {
-        Ok(t) => Ok(f(t)),
-        Err(e) => Err(PoisonError::new(f(e.into_inner())))
+    /// Get a poison lock on this poison which poisons according to `mode`.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_mode(&mut self, mode: PoisonMode) -> LockResult<PoisonGuard<'_, T>> {
+        let data = &mut self.data;
+        map_result(self.raw.lock_mode(mode), move |lock| PoisonGuard { data, guard: lock, deferred: None })
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::sync::{Mutex, Arc};
-    use std::thread;
+    /// Get a poison lock on this poison with a deadline.
+    ///
+    /// See RawPoison::lock_deadline.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_deadline(&mut self, deadline: Instant) -> LockResult<PoisonGuard<'_, T>> {
+        let data = &mut self.data;
+        map_result(self.raw.lock_deadline(deadline), move |lock| PoisonGuard { data, guard: lock, deferred: None })
+    }
 
-    use {Poison, RawPoison};
+    /// Get a poison lock on this poison which measures how long it is held.
+    ///
+    /// When the guard is dropped, the time since it was created is passed to
+    /// the callback set with set_on_guard_duration, if any. Untimed guards do
+    /// not pay for the measurement.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_timed(&mut self) -> LockResult<TimedPoisonGuard<'_, T>> {
+        map_result(self.lock(), |guard| TimedPoisonGuard { guard, start: Instant::now() })
+    }
 
-    #[test]
-    fn test_poison() {
-        let x1 = Arc::new(Mutex::new(Poison::new(12)));
-        let x2 = x1.clone();
+    /// Get a poison lock on this poison which checks an invariant when dropped.
+    ///
+    /// In addition to poisoning on panic, the guard runs `inv` on the data
+    /// when it is dropped and poisons the Poison if it returns false, catching
+    /// mutations which leave the data invalid without panicking. `inv` is not
+    /// run while panicking, since the guard poisons the Poison anyway.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_checked<'poison, F>(&'poison mut self, inv: F)
+                                    -> LockResult<CheckedPoisonGuard<'poison, T, F>>
+                                    where F: Fn(&T) -> bool + 'poison {
+        map_result(self.lock(), move |guard| CheckedPoisonGuard { guard, inv })
+    }
 
-        thread::spawn(move || {
-            let mut _ml = x1.lock().unwrap();
-            let _pl = _ml.lock().unwrap();
-            panic!();
-        }).join().unwrap_err();
+    /// Get a poison lock on this poison which checks an invariant on a sample
+    /// of drops.
+    ///
+    /// Like lock_checked, but `inv` only runs with probability `rate` when the
+    /// guard is dropped, for hot paths where checking on every drop is too
+    /// expensive. A `rate` of 1 always checks and a `rate` of 0 never does.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_sampled<'poison, F>(&'poison mut self, rate: f64, inv: F)
+                                    -> LockResult<SampledPoisonGuard<'poison, T, F>>
+                                    where F: Fn(&T) -> bool + 'poison {
+        map_result(self.lock(), move |guard| SampledPoisonGuard { guard, rate, inv })
+    }
 
-        match x2.lock() {
-            Err(mut p) => {
-                p.get_mut().lock().unwrap_err();
-                p.get_mut().heal();
-                p.get_mut().lock().unwrap();
-            },
-            Ok(_) => panic!("Mutex not poisoned?")
-        };
+    /// Get a poison lock on this poison which rolls back the data on panic.
+    ///
+    /// The guard clones the data when it is created. If the current thread
+    /// panics before the guard is dropped, the clone is restored before the
+    /// Poison is poisoned, so while still poisoned, the data is back in the
+    /// state it was in before the failed mutation.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_rollback(&mut self) -> LockResult<RollbackPoisonGuard<'_, T>> where T: Clone {
+        map_result(self.lock(), |guard| {
+            let snapshot = guard.get().clone();
+            RollbackPoisonGuard { guard, snapshot: Some(snapshot) }
+        })
     }
 
-    #[test]
-    fn test_raw_poison() {
-        let x1 = Arc::new(Mutex::new(RawPoison::new()));
-        let x2 = x1.clone();
+    /// Get a poison lock on this poison which also poisons on cancellation.
+    ///
+    /// In addition to poisoning on panic, the guard will poison the Poison if
+    /// `token` is set when it is dropped, indicating the operation was
+    /// cancelled mid-flight. A panic and a cancellation poison only once, and
+    /// a frozen Poison is poisoned by neither.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_cancellable(&mut self, token: Arc<AtomicBool>)
+                            -> LockResult<CancellablePoisonGuard<'_, T>> {
+        map_result(self.lock(), move |guard| CancellablePoisonGuard { guard, token })
+    }
 
-        thread::spawn(move || {
-            let mut _ml = x1.lock().unwrap();
-            let _pl = _ml.lock().unwrap();
-            panic!();
-        }).join().unwrap_err();
+    /// Get a poison lock on this poison, attempting recovery if poisoned.
+    ///
+    /// If the Poison is poisoned, `recover` is run on the data. If it returns
+    /// true the Poison is healed and a guard is returned, otherwise the
+    /// Poison stays poisoned and PoisonError is returned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_or_recover<F: FnOnce(&mut T) -> bool>(&mut self, recover: F) -> LockResult<PoisonGuard<'_, T>> {
+        if self.raw.poisoned && recover(&mut self.data) {
+            self.raw.heal();
+        }
 
-        match x2.lock() {
-            Err(mut p) => {
-                p.get_mut().lock().unwrap_err();
-                p.get_mut().heal();
-                p.get_mut().lock().unwrap();
-            },
-            Ok(_) => panic!("Mutex not poisoned?")
-        };
+        self.lock()
     }
-}
 
+    /// Get a poison lock on this poison, healing it with some probability if
+    /// poisoned.
+    ///
+    /// If the Poison is poisoned, it is healed with probability `heal_prob`
+    /// and a guard is returned, otherwise PoisonError is returned. This is
+    /// best-effort recovery from transient poisoning without a supervisor: the
+    /// data is not checked before healing, so prefer lock_or_recover unless
+    /// the data is validated some other way.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_self_healing(&mut self, heal_prob: f64) -> LockResult<PoisonGuard<'_, T>> {
+        if self.raw.poisoned && sample::sample(heal_prob) {
+            self.raw.heal();
+        }
+
+        self.lock()
+    }
+
+    /// Run an async operation over the data, holding the poison lock until it
+    /// completes.
+    ///
+    /// Only available with the `async` feature. In async code a panic is not
+    /// the only way to abandon an operation midway, so instead of detecting
+    /// panics, the Poison is poisoned if the returned future is dropped before
+    /// it completes, whether because it was cancelled or because polling it
+    /// panicked. A frozen Poison is not poisoned.
+    ///
+    /// The future resolves to PoisonError if the Poison was poisoned when
+    /// with_async was called; the operation still runs.
+    #[cfg(feature = "async")]
+    pub fn with_async<'poison, F, Fut>(&'poison mut self, f: F) -> WithAsync<'poison, Fut>
+                                       where F: FnOnce(&'poison mut T) -> Fut, Fut: Future {
+        let poisoned = self.raw.poisoned;
+        let fut = f(&mut self.data);
+
+        WithAsync { fut, poison: &mut self.raw, poisoned, done: false }
+    }
+
+    /// Get a poison lock on this poison, converting poisoning into a custom error.
+    ///
+    /// If the Poison is poisoned, `f` is called with the guard, so it can
+    /// inspect or repair the data, and its result is returned as the error.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_or_else<E, F>(&mut self, f: F) -> Result<PoisonGuard<'_, T>, E>
+                              where F: FnOnce(PoisonGuard<'_, T>) -> E {
+        self.lock().map_err(|err| f(err.into_inner()))
+    }
+
+    /// Get a poison lock on this poison, unless a guard on it is already live.
+    ///
+    /// Returns GuardBusy if a guard on this Poison was created and never
+    /// dropped. Since lock borrows the Poison mutably, in safe code this only
+    /// happens when a guard has been leaked, for instance by mem::forget.
+    /// Guards on other Poisons do not make this Poison busy.
+    ///
+    /// Otherwise returns the result of lock.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn try_guard(&mut self) -> Result<LockResult<PoisonGuard<'_, T>>, GuardBusy> {
+        if self.raw.guarded { return Err(GuardBusy) }
+        Ok(self.lock())
+    }
+
+    /// Get a poison lock on this poison which accesses the data through `f`.
+    ///
+    /// The projection is applied each time the data is accessed, while
+    /// poisoning stays tied to this Poison.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_as<U: ?Sized, F>(&mut self, f: F) -> LockResult<MappedPoisonGuard<'_, T, U, F>>
+                                 where F: Fn(&mut T) -> &mut U {
+        map_result(self.lock(), move |guard| MappedPoisonGuard { guard, map: f, _marker: PhantomData })
+    }
+
+    /// Heal the Poison, unpoisoning it if it is poisoned.
+    #[inline]
+    pub fn heal(&mut self) {
+        self.raw.heal();
+    }
+
+    /// Heal the Poison only if the data passes validation.
+    ///
+    /// If the Poison is poisoned, `valid` is run on the data and the Poison is
+    /// healed only if it returns true. Returns whether this call healed the
+    /// Poison, so false if it was not poisoned or the data failed validation.
+    #[inline]
+    pub fn try_heal<F: FnOnce(&T) -> bool>(&mut self, valid: F) -> bool {
+        let healed = self.raw.poisoned && valid(&self.data);
+        if healed { self.raw.heal(); }
+        healed
+    }
+
+    /// Run recovery work under a guard, healing the Poison if it completes.
+    ///
+    /// If `f` returns normally the Poison is healed. If it panics the guard
+    /// poisons the Poison, or leaves it poisoned, as usual.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn heal_scope<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        let result = {
+            let mut guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+            f(guard.get_mut())
+        };
+
+        self.heal();
+        result
+    }
+
+    /// Set a callback to run whenever the Poison heals.
+    ///
+    /// See RawPoison::set_on_heal.
+    #[inline]
+    pub fn set_on_heal<F: FnMut() + Send + 'static>(&mut self, f: F) {
+        self.raw.set_on_heal(f);
+    }
+
+    /// Set a callback to run with the time each timed guard was held for.
+    ///
+    /// See RawPoison::set_on_guard_duration.
+    #[inline]
+    pub fn set_on_guard_duration<F: FnMut(Duration) + Send + 'static>(&mut self, f: F) {
+        self.raw.set_on_guard_duration(f);
+    }
+
+    /// Check if the Poison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.is_poisoned()
+    }
+
+    /// Mark the Poison as degraded.
+    ///
+    /// See RawPoison::degrade.
+    #[inline]
+    pub fn degrade(&mut self) {
+        self.raw.degrade()
+    }
+
+    /// Check if the Poison is degraded.
+    #[inline]
+    pub fn is_degraded(&self) -> bool {
+        self.raw.is_degraded()
+    }
+
+    /// Set whether the Poison is in observe-only mode.
+    ///
+    /// See RawPoison::set_observe_only.
+    #[inline]
+    pub fn set_observe_only(&mut self, observe_only: bool) {
+        self.raw.set_observe_only(observe_only)
+    }
+
+    /// Check if the Poison is in observe-only mode.
+    #[inline]
+    pub fn is_observe_only(&self) -> bool {
+        self.raw.is_observe_only()
+    }
+
+    /// Get the number of times this Poison would have been poisoned while in
+    /// observe-only mode.
+    ///
+    /// See RawPoison::observed_poisonings.
+    #[inline]
+    pub fn observed_poisonings(&self) -> usize {
+        self.raw.observed_poisonings()
+    }
+
+    /// Freeze the Poison, so guards will not poison it when dropped.
+    ///
+    /// See RawPoison::freeze.
+    #[inline]
+    pub fn freeze(&mut self) {
+        self.raw.freeze();
+    }
+
+    /// Thaw a frozen Poison, so guards will poison it again.
+    #[inline]
+    pub fn thaw(&mut self) {
+        self.raw.thaw();
+    }
+
+    /// Check if the Poison is frozen.
+    #[inline]
+    pub fn is_frozen(&self) -> bool {
+        self.raw.is_frozen()
+    }
+
+    /// Heal the Poison and any poison-bearing data it contains.
+    #[inline]
+    pub fn deep_heal(&mut self) where T: DeepHeal {
+        self.raw.heal();
+        self.data.deep_heal();
+    }
+
+    /// Get the message of the panic which poisoned this Poison.
+    ///
+    /// See RawPoison::poison_reason.
+    #[cfg(feature = "reason")]
+    #[inline]
+    pub fn poison_reason(&self) -> Option<&str> {
+        self.raw.poison_reason()
+    }
+
+    /// Get the reasons this Poison has been poisoned for, oldest first.
+    ///
+    /// See RawPoison::poison_reasons.
+    #[cfg(feature = "reason")]
+    #[inline]
+    pub fn poison_reasons(&self) -> &[String] {
+        self.raw.poison_reasons()
+    }
+
+    /// Fully reset the Poison, healing it and clearing its recorded
+    /// diagnostics.
+    ///
+    /// See RawPoison::reset.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.raw.reset()
+    }
+
+    /// Get the backtrace of the panic which poisoned this Poison.
+    ///
+    /// See RawPoison::poison_backtrace.
+    #[cfg(feature = "backtrace")]
+    #[inline]
+    pub fn poison_backtrace(&self) -> Option<&Backtrace> {
+        self.raw.poison_backtrace()
+    }
+
+    /// Get the time at which this Poison became poisoned.
+    ///
+    /// See RawPoison::poisoned_since.
+    #[cfg(feature = "timestamp")]
+    #[inline]
+    pub fn poisoned_since(&self) -> Option<Instant> {
+        self.raw.poisoned_since()
+    }
+
+    /// Heal the Poison if it has been poisoned for at least `dur`.
+    ///
+    /// See RawPoison::heal_if_older_than.
+    #[cfg(feature = "timestamp")]
+    #[inline]
+    pub fn heal_if_older_than(&mut self, dur: Duration) -> bool {
+        self.raw.heal_if_older_than(dur)
+    }
+
+    /// Get a snapshot of all of the diagnostic state of this Poison.
+    ///
+    /// See RawPoison::debug_state.
+    #[inline]
+    pub fn debug_state(&self) -> PoisonState {
+        self.raw.debug_state()
+    }
+
+    /// Get the most recent transitions of this Poison, oldest first.
+    ///
+    /// See RawPoison::history.
+    #[cfg(feature = "audit")]
+    #[inline]
+    pub fn history(&self) -> &[PoisonTransition] {
+        self.raw.history()
+    }
+
+    /// Get an immutable reference to the data in this poison.
+    ///
+    /// There is no guard for an immutable reference, since the data must either
+    /// be immutable or internally poisoned if it has interior mutability.
+    #[inline(always)]
+    pub fn get(&self) -> LockResult<&T> {
+        if self.raw.poisoned { return err_poisoned(&self.data) }
+        Ok(&self.data)
+    }
+
+    /// Get an immutable reference to the data without checking the poison state.
+    ///
+    /// Unlike get, never reports poisoning, for hot read loops which have
+    /// already checked is_poisoned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the Poison is not poisoned, or that reading
+    /// possibly invalid data is acceptable for this read.
+    #[inline(always)]
+    pub unsafe fn get_unchecked(&self) -> &T { &self.data }
+
+    /// Get a reference derived from the data in this poison.
+    ///
+    /// Like get, but `f` projects the reference, for instance to a field,
+    /// and the result keeps the poison state.
+    #[inline]
+    pub fn map_ref<U: ?Sized, F: FnOnce(&T) -> &U>(&self, f: F) -> LockResult<&U> {
+        map_result(self.get(), f)
+    }
+
+    /// Get an immutable reference to the data along with the poison state.
+    ///
+    /// Useful when reading the data and branching on poison state without
+    /// matching on the LockResult returned by get.
+    #[inline]
+    pub fn get_pair(&self) -> (&T, bool) {
+        (&self.data, self.is_poisoned())
+    }
+
+    /// Check if the Poison is not poisoned and its data equals `x`.
+    ///
+    /// A poisoned Poison never contains any value, since its data may be
+    /// corrupt.
+    #[inline]
+    pub fn contains<U: ?Sized>(&self, x: &U) -> bool where T: PartialEq<U> {
+        !self.raw.poisoned && self.data == *x
+    }
+
+    /// Get a reference to the data as a plain Result.
+    ///
+    /// Returns Ok if the Poison is not poisoned and Err if it is, with a
+    /// reference to the data in either case.
+    #[inline]
+    pub fn as_result(&self) -> Result<&T, &T> {
+        if self.raw.poisoned {
+            Err(&self.data)
+        } else {
+            Ok(&self.data)
+        }
+    }
+
+    /// Get an immutable reference to the data, or a Poisoned error.
+    ///
+    /// Unlike get, the error does not carry the data, so it composes with `?`
+    /// in functions returning `Result<_, Box<dyn Error>>`.
+    #[inline(always)]
+    pub fn checked_get(&self) -> Result<&T, Poisoned> {
+        if self.raw.poisoned { return Err(Poisoned) }
+        Ok(&self.data)
+    }
+
+    /// Get an immutable reference to the data, converting poisoning into
+    /// Poisoned.
+    ///
+    /// Shorthand for `get().map_err(Poisoned::from)`, for read sites in
+    /// functions returning `Result<_, Poisoned>`. Equivalent to checked_get.
+    #[inline(always)]
+    pub fn get_or_poisoned(&self) -> Result<&T, Poisoned> {
+        self.get().map_err(Poisoned::from)
+    }
+
+    /// Get a mutable reference without a guard.
+    ///
+    /// Should only be used in combination with PoisonGuard::into_raw.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that any mutation through the returned reference
+    /// is covered by a live RawPoisonGuard, or is otherwise externally poisoned.
+    pub unsafe fn get_mut(&mut self) -> &mut T { &mut self.data }
+
+    /// Get a mutable reference without a guard, or None if poisoned.
+    ///
+    /// Checks the poison state, returning an Option for use with `if let` and
+    /// `?`. Since there is no guard, a panic while mutating through the
+    /// returned reference does not poison the Poison, as with get_mut_or_init.
+    #[inline]
+    pub fn data_mut(&mut self) -> Option<&mut T> {
+        if self.raw.poisoned { None } else { Some(&mut self.data) }
+    }
+
+    /// Run a closure over the data under a guard, rolling back on panic.
+    ///
+    /// `snapshot` is called with the data before `body` runs and returns a
+    /// rollback closure. If `body` panics, the rollback closure is used to
+    /// restore the data before the Poison becomes poisoned, so the data is
+    /// left in its prior state even though the Poison is poisoned.
+    ///
+    /// The rollback closure runs during unwinding and must not panic.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; `body` still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn guard_with_rollback<R, F, G, S>(&mut self, snapshot: G, body: F) -> LockResult<R>
+                                           where F: FnOnce(&mut T) -> R,
+                                                 G: FnOnce(&T) -> S,
+                                                 S: FnOnce(&mut T) {
+        let restore = snapshot(&self.data);
+
+        map_result(self.lock(), move |mut guard| {
+            let rollback = Rollback {
+                data: guard.get_mut(),
+                restore: Some(restore),
+                panicking: thread::panicking()
+            };
+
+            body(&mut *rollback.data)
+        })
+    }
+
+    /// Arm the Poison for the lifetime of the returned scope.
+    ///
+    /// While the scope is alive, a panic anywhere on the current thread will
+    /// poison the Poison when the scope drops, even if the Poison is frozen
+    /// or already poisoned. The previous frozen state is restored when the
+    /// scope is dropped. Useful for wrapping large sections of code without
+    /// threading a guard through every call.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn arm_scope(&mut self) -> PoisonArmScope<'_, T> {
+        let was_frozen = self.raw.frozen;
+        self.raw.frozen = false;
+
+        PoisonArmScope {
+            guard: PoisonGuard {
+                data: &mut self.data,
+                guard: RawPoisonGuard::new(&mut self.raw, PoisonMode::OnPanic),
+                deferred: None
+            },
+            was_frozen
+        }
+    }
+
+    /// Get a guard over the two halves of the contained slice.
+    ///
+    /// Splits the slice at `mid` like `split_at_mut`, with both halves
+    /// sharing a single underlying guard, so a panic while mutating either
+    /// half poisons the whole Poison.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mid` is greater than the length of the slice.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn split_at_guard<E>(&mut self, mid: usize) -> LockResult<PoisonSplitGuard<'_, E>>
+                             where T: AsMut<[E]> {
+        let (left, right) = self.data.as_mut().split_at_mut(mid);
+        map_result(self.raw.lock(), move |guard| PoisonSplitGuard { left, right, guard })
+    }
+
+    /// Run several mutations under a single guard.
+    ///
+    /// `f` receives a PoisonBatch whose steps all run under the same guard,
+    /// so a panic in any step poisons the Poison once, without repeatedly
+    /// locking and unlocking between steps.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; `f` still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn batch<F: FnOnce(&mut PoisonBatch<'_, T>)>(&mut self, f: F) -> LockResult<()> {
+        map_result(self.lock(), move |guard| f(&mut PoisonBatch { guard }))
+    }
+
+    /// Run two closures in turn under a single guard.
+    ///
+    /// A two-step version of batch: `f` runs first, then `g` produces the
+    /// result. A panic in either poisons the Poison once.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; both closures still run.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn with_then<R, F, G>(&mut self, f: F, g: G) -> LockResult<R>
+                              where F: FnOnce(&mut T), G: FnOnce(&mut T) -> R {
+        map_result(self.lock(), move |mut guard| {
+            f(guard.get_mut());
+            g(guard.get_mut())
+        })
+    }
+
+    /// Run a queued command over the data under a guard.
+    ///
+    /// A panic in the command poisons the Poison, so queues of commands do not
+    /// need to manage guards themselves.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; the command still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn run_command(&mut self, cmd: PoisonCommand<T>) -> LockResult<()> {
+        map_result(self.lock(), move |mut guard| cmd(guard.get_mut()))
+    }
+
+    /// Apply a prepared operation to the data under a guard.
+    ///
+    /// A panic in the operation poisons the Poison, so the same operation
+    /// behaves consistently whichever Poison it is applied to.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; the operation still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn apply<Args, R>(&mut self, op: &PoisonOp<T, Args, R>, args: Args) -> LockResult<R> {
+        map_result(self.lock(), move |mut guard| (op.f)(guard.get_mut(), args))
+    }
+
+    /// Run a closure which takes ownership of the data under a guard.
+    ///
+    /// `f` is given the data by value and must hand it back along with its
+    /// result. While `f` runs, the Poison holds `T::default()` in place of the
+    /// data, so it is never left uninitialized: if `f` panics, the data it
+    /// was given is dropped during unwinding, and the Poison is poisoned with
+    /// the default value in its place.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; `f` still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn consume<R, F: FnOnce(T) -> (T, R)>(&mut self, f: F) -> LockResult<R> where T: Default {
+        map_result(self.lock(), move |mut guard| {
+            let (data, result) = f(mem::take(guard.get_mut()));
+            *guard.get_mut() = data;
+            result
+        })
+    }
+
+    /// Run a closure under a guard with an operation budget.
+    ///
+    /// `f` is expected to charge the Budget for the work it does. Exceeding
+    /// `max` panics, which poisons the Poison, as a cooperative safety valve
+    /// against runaway loops or mutations.
+    ///
+    /// Returns PoisonError if the Poison is poisoned; `f` still runs.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn with_budget<R, F>(&mut self, max: u64, f: F) -> LockResult<R>
+                             where F: FnOnce(&mut T, &mut Budget) -> R {
+        let mut budget = Budget { used: 0, max };
+        map_result(self.lock(), move |mut guard| f(guard.get_mut(), &mut budget))
+    }
+
+    /// Run a fallible closure over the data under a guard.
+    ///
+    /// If the Poison is already poisoned, the closure is not run and
+    /// TryWithError::Poisoned is returned. Otherwise, errors from the closure
+    /// are returned as TryWithError::Inner, and a panic in the closure poisons
+    /// the Poison.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn try_with<R, E, F>(&mut self, f: F) -> Result<R, TryWithError<E>>
+                             where F: FnOnce(&mut T) -> Result<R, E> {
+        match self.lock() {
+            Ok(mut guard) => f(guard.get_mut()).map_err(TryWithError::Inner),
+            Err(_) => Err(TryWithError::Poisoned)
+        }
+    }
+
+    /// Run a fallible closure directly over the data, poisoning on error.
+    ///
+    /// If the closure returns Err, the Poison is poisoned before the error is
+    /// returned. No guard is created, so a panic in the closure will NOT
+    /// poison the Poison; use try_with when panics should poison.
+    ///
+    /// The closure runs regardless of the current poison state.
+    #[inline]
+    pub fn try_get_mut<R, E, F>(&mut self, f: F) -> Result<R, E>
+                                where F: FnOnce(&mut T) -> Result<R, E> {
+        let result = f(&mut self.data);
+        if result.is_err() { self.raw.poison(); }
+        result
+    }
+
+    /// Check the invariants of the data, poisoning the Poison if they fail.
+    ///
+    /// Runs Invariant::check on the data and returns its result. A passing
+    /// check does not heal an already poisoned Poison.
+    pub fn validate(&mut self) -> Result<(), InvariantError> where T: Invariant {
+        let result = self.data.check();
+        if result.is_err() { self.raw.poison(); }
+        result
+    }
+
+    /// Run a closure under a guard, healing and retrying if it panics.
+    ///
+    /// `f` is attempted up to `attempts` times, and at least once. Each panic
+    /// is caught and poisons the Poison, which is then healed before the next
+    /// attempt. Returns the result of the first successful attempt, or the
+    /// payload of the last panic, in which case the Poison stays poisoned.
+    ///
+    /// The data is not reset between attempts, so a retried `f` sees whatever
+    /// the panicking attempt left behind. Combine with recover_with if each
+    /// attempt needs a fresh state.
+    pub fn with_retry<R, F>(&mut self, attempts: usize, mut f: F) -> thread::Result<R>
+                            where F: FnMut(&mut T) -> R + UnwindSafe {
+        let mut attempt = 0;
+
+        loop {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+                f(guard.get_mut())
+            }));
+
+            attempt += 1;
+            if result.is_ok() || attempt >= attempts { return result }
+
+            self.heal();
+        }
+    }
+
+    /// Run a closure under a guard, catching a panic and recording its message.
+    ///
+    /// If `f` panics, the panic is caught and poisons the Poison as usual. The
+    /// panic message is returned and, with the `reason` feature, recorded as
+    /// the reason, available through poison_reason, if this call is what
+    /// poisoned the Poison. A panic in a Poison which was already poisoned
+    /// keeps the reason of the original poisoning. Payloads which are not
+    /// strings are reported as "non-string panic".
+    pub fn scope_capturing<F>(&mut self, f: F) -> Result<(), String>
+                              where F: FnOnce(&mut T) + UnwindSafe {
+        #[cfg(feature = "reason")]
+        let was_poisoned = self.raw.poisoned;
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+            f(guard.get_mut())
+        }));
+
+        result.map_err(|payload| {
+            let message = panic_message(&*payload);
+            #[cfg(feature = "reason")]
+            { if !was_poisoned && self.raw.poisoned { self.raw.set_reason(message.clone()); } }
+            message
+        })
+    }
+}
+
+impl<T> Poison<Poison<T>> {
+    /// Collapse a nested Poison into a single Poison.
+    ///
+    /// The result is poisoned if either the outer or inner Poison is poisoned.
+    /// Everything else, such as whether it is frozen and its callbacks, comes
+    /// from the inner Poison.
+    #[inline]
+    pub fn flatten(self) -> Poison<T> {
+        let Poison { raw, data: mut inner } = self;
+
+        if raw.poisoned && !inner.raw.poisoned {
+            inner.raw.inherit_poison(raw);
+        }
+
+        inner
+    }
+}
+
+impl Poison<Box<dyn Any + Send>> {
+    /// Recover the concrete type of a Poison erased with erase.
+    ///
+    /// Keeps the poison state. If the data is not a `U`, the erased Poison is
+    /// returned unchanged.
+    #[inline]
+    // Mirrors Box::downcast; the error is only large with optional features.
+    #[allow(clippy::result_large_err)]
+    pub fn downcast<U: Any>(self) -> Result<Poison<U>, Poison<Box<dyn Any + Send>>> {
+        let Poison { raw, data } = self;
+
+        match data.downcast::<U>() {
+            Ok(data) => Ok(Poison { raw, data: *data }),
+            Err(data) => Err(Poison { raw, data })
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> Poison<HashMap<K, V, S>> {
+    /// Get the entry for a key in the map, for in-place manipulation.
+    ///
+    /// The returned PoisonEntry holds a guard, so if the current thread
+    /// panics while manipulating the entry, the whole Poison is poisoned.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn entry(&mut self, key: K) -> LockResult<PoisonEntry<'_, K, V>> {
+        let data = &mut self.data;
+        map_result(self.raw.lock(), move |guard| PoisonEntry { entry: data.entry(key), guard })
+    }
+}
+
+impl<T> Poison<Option<T>> {
+    /// Get a mutable reference to the contained value, initializing it if absent.
+    ///
+    /// The value is initialized with `init` if it is None, regardless of the
+    /// poison state. The returned reference is not guarded, so panics while
+    /// using it will not poison the Poison.
+    ///
+    /// Returns PoisonError if the Poison is poisoned.
+    #[inline]
+    pub fn get_mut_or_init<F: FnOnce() -> T>(&mut self, init: F) -> LockResult<&mut T> {
+        let poisoned = self.raw.poisoned;
+        let data = self.data.get_or_insert_with(init);
+
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T> Poison<Vec<T>> {
+    /// Map each element of the contained vector, keeping the poison state.
+    ///
+    /// If `f` panics partway through, the panic propagates and the partially
+    /// mapped elements are dropped along with this Poison, so a half-mapped
+    /// vector can never be observed.
+    #[inline]
+    pub fn map_elems<U, F: FnMut(T) -> U>(self, f: F) -> Poison<Vec<U>> {
+        Poison { raw: self.raw, data: self.data.into_iter().map(f).collect() }
+    }
+
+    /// Get a poison lock on a single element of the contained vector.
+    ///
+    /// The guard shares the Poison's underlying guard, so a panic while
+    /// mutating the element poisons the whole vector.
+    ///
+    /// Returns OutOfBounds, without poisoning, if `idx` is out of bounds, or
+    /// PoisonError if the Poison is poisoned.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_index(&mut self, idx: usize) -> Result<LockResult<PoisonGuard<'_, T>>, OutOfBounds> {
+        let len = self.data.len();
+        let data = self.data.get_mut(idx).ok_or(OutOfBounds { index: idx, len })?;
+
+        Ok(map_result(self.raw.lock(), move |lock| PoisonGuard { data, guard: lock, deferred: None }))
+    }
+}
+
+/// Allows coercing a Poison wrapping a pointer, such as `Poison<Box<[T; N]>>`
+/// to `Poison<Box<[T]>>`, as with `Cell`.
+#[cfg(feature = "nightly")]
+impl<T: CoerceUnsized<U>, U> CoerceUnsized<Poison<U>> for Poison<T> {}
+
+/// Allows coercing a guard on sized data to a guard on unsized data,
+/// such as `PoisonGuard<[T; N]>` to `PoisonGuard<[T]>`.
+#[cfg(feature = "nightly")]
+impl<'poison, T, U> CoerceUnsized<PoisonGuard<'poison, U>> for PoisonGuard<'poison, T>
+    where T: ?Sized + Unsize<U>, U: ?Sized {}
+
+/// Two Poisons are equal if they are in the same poison state and their
+/// data is equal.
+impl<T: ?Sized + PartialEq> PartialEq for Poison<T> {
+    #[inline]
+    fn eq(&self, other: &Poison<T>) -> bool {
+        self.raw.poisoned == other.raw.poisoned && self.data == other.data
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Poison<T> {}
+
+/// Poisons are ordered first by poison state, with every non-poisoned Poison
+/// ordered before every poisoned Poison, and then by their data.
+///
+/// Sorting a collection of Poisons groups the poisoned entries at the end.
+impl<T: ?Sized + PartialOrd> PartialOrd for Poison<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &Poison<T>) -> Option<Ordering> {
+        match self.raw.poisoned.cmp(&other.raw.poisoned) {
+            Ordering::Equal => self.data.partial_cmp(&other.data),
+            ordering => Some(ordering)
+        }
+    }
+}
+
+/// See the PartialOrd implementation for the ordering used.
+impl<T: ?Sized + Ord> Ord for Poison<T> {
+    #[inline]
+    fn cmp(&self, other: &Poison<T>) -> Ordering {
+        self.raw.poisoned.cmp(&other.raw.poisoned)
+            .then_with(|| self.data.cmp(&other.data))
+    }
+}
+
+/// Extends the data under a guard, so the Poison becomes poisoned if the
+/// iterator panics partway through.
+///
+/// Since Extend cannot return a LockResult, extending an already poisoned
+/// Poison silently proceeds, leaving it poisoned.
+impl<A, T: ?Sized + Extend<A>> Extend<A> for Poison<T> {
+    #[cfg_attr(feature = "tracing", track_caller)]
+    fn extend<I: IntoIterator<Item = A>>(&mut self, iter: I) {
+        let _guard = RawPoisonGuard::new(&mut self.raw, PoisonMode::OnPanic);
+        self.data.extend(iter);
+    }
+}
+
+impl<'poison, T: 'poison> PoisonGuard<'poison, T> {
+    /// Register a closure to run over the data when this guard drops.
+    ///
+    /// The closure runs first thing in drop, whether or not the thread is
+    /// panicking, and before the guard decides whether to poison, so it sees
+    /// the data in whatever state the guarded section left it. If the closure
+    /// itself panics, the guard then poisons as it would for any other panic.
+    ///
+    /// Only one closure can be deferred; registering another replaces it. The
+    /// closure is discarded, without running, if this guard is converted into
+    /// a raw, read-only or mapped guard.
+    ///
+    /// The closure must be Send, so that the guard stays Send and Sync.
+    pub fn defer<F: FnOnce(&mut T) + Send + 'poison>(&mut self, f: F) {
+        // The pointer is always to a T, even if the guard has been unsized,
+        // since unsizing does not move the data.
+        self.deferred = Some(Callback(Box::new(move |data| f(unsafe { &mut *(data as *mut T) }))));
+    }
+}
+
+impl<'poison, T: ?Sized> Drop for PoisonGuard<'poison, T> {
+    fn drop(&mut self) {
+        if let Some(Callback(deferred)) = self.deferred.take() {
+            deferred(&mut *self.data as *mut T as *mut ());
+        }
+    }
+}
+
+impl<'poison, T: ?Sized + fmt::Debug> fmt::Debug for PoisonGuard<'poison, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoisonGuard")
+            .field("data", &self.data)
+            .field("guard", &self.guard)
+            .field("deferred", &self.deferred.is_some())
+            .finish()
+    }
+}
+
+impl<'poison, T: ?Sized> PoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.data }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.data }
+
+    /// Get a reference that escapes the guard.
+    ///
+    /// Should only be used if the data will be externally poisoned.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the returned reference is not used to leave
+    /// the data in an invalid state unless it is poisoned by some other means.
+    pub unsafe fn into_mut(self) -> &'poison mut T { self.into_parts().0 }
+
+    /// Get the raw poison guard.
+    pub fn into_raw(self) -> RawPoisonGuard<'poison> { self.into_parts().1 }
+
+    /// Commit the guard, so it will not poison when dropped.
+    ///
+    /// See RawPoisonGuard::commit.
+    pub fn commit(&mut self) -> bool { self.guard.commit() }
+
+    /// Check if the associated Poison is degraded.
+    pub fn is_degraded(&self) -> bool { self.guard.is_degraded() }
+
+    /// End the guarded section with a fallible final step.
+    ///
+    /// Runs `f` over the data, then drops the guard, poisoning as usual if
+    /// `f` panics, and returns the result of `f`.
+    pub fn finish<R, E, F: FnOnce(&mut T) -> Result<R, E>>(mut self, f: F) -> Result<R, E> {
+        f(self.get_mut())
+    }
+
+    /// Get the time left before this guard's deadline.
+    ///
+    /// See RawPoisonGuard::time_left.
+    pub fn time_left(&self) -> Option<Duration> { self.guard.time_left() }
+
+    // Split the guard into its parts, discarding any deferred closure.
+    fn into_parts(self) -> (&'poison mut T, RawPoisonGuard<'poison>) {
+        let mut this = ManuallyDrop::new(self);
+        drop(this.deferred.take());
+        unsafe { (ptr::read(&this.data), ptr::read(&this.guard)) }
+    }
+
+    /// Downgrade this guard to a read-only guard.
+    ///
+    /// The logical lock is held until the read guard is dropped, but since
+    /// mutation is finished, the read guard will not poison on panic or if
+    /// it was uncommitted.
+    pub fn downgrade(self) -> PoisonReadGuard<'poison, T> {
+        let (data, mut guard) = self.into_parts();
+        guard.disarm();
+        PoisonReadGuard { data, guard }
+    }
+
+    /// Reborrow this guard for a shorter lifetime.
+    ///
+    /// The new guard shares the same underlying RawPoison. If a panic unwinds
+    /// through the reborrowed guard, it poisons the Poison when it drops; the
+    /// parent guard will then find the Poison already poisoned, so only the
+    /// first guard dropped during the panic records the poisoning.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn reborrow(&mut self) -> PoisonGuard<'_, T> {
+        PoisonGuard { data: &mut *self.data, guard: self.guard.reborrow(), deferred: None }
+    }
+}
+
+/// Data with checkable invariants.
+///
+/// Implemented by types that can verify their own consistency, for use with
+/// Poison::validate.
+pub trait Invariant {
+    /// Check that the invariants of this value hold.
+    fn check(&self) -> Result<(), InvariantError>;
+}
+
+/// An error describing a violated invariant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantError {
+    message: String
+}
+
+impl InvariantError {
+    /// Create a new InvariantError with a description of the violation.
+    pub fn new<M: Into<String>>(message: M) -> InvariantError {
+        InvariantError { message: message.into() }
+    }
+
+    /// Get the description of the violation.
+    pub fn message(&self) -> &str { &self.message }
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invariant violated: {}", self.message)
+    }
+}
+
+impl Error for InvariantError {}
+
+/// Structures which can heal themselves and any poisons they contain.
+///
+/// Implemented for the poison types, for common containers of types which
+/// implement DeepHeal, and as a no-op for primitive types, so composite and
+/// tree-shaped structures can recover fully in a single call.
+pub trait DeepHeal {
+    /// Heal this value and everything it contains.
+    fn deep_heal(&mut self);
+}
+
+impl<T: ?Sized + DeepHeal> DeepHeal for Poison<T> {
+    #[inline]
+    fn deep_heal(&mut self) { Poison::deep_heal(self) }
+}
+
+impl DeepHeal for RawPoison {
+    #[inline]
+    fn deep_heal(&mut self) { self.heal() }
+}
+
+impl DeepHeal for AtomicRawPoison {
+    #[inline]
+    fn deep_heal(&mut self) { self.heal() }
+}
+
+impl<T: ?Sized + DeepHeal> DeepHeal for Box<T> {
+    #[inline]
+    fn deep_heal(&mut self) { (**self).deep_heal() }
+}
+
+impl<T: DeepHeal> DeepHeal for Option<T> {
+    #[inline]
+    fn deep_heal(&mut self) {
+        if let Some(ref mut val) = *self { val.deep_heal() }
+    }
+}
+
+impl<T: DeepHeal> DeepHeal for [T] {
+    #[inline]
+    fn deep_heal(&mut self) {
+        for val in self { val.deep_heal() }
+    }
+}
+
+impl<T: DeepHeal, const N: usize> DeepHeal for [T; N] {
+    #[inline]
+    fn deep_heal(&mut self) { self[..].deep_heal() }
+}
+
+impl<T: DeepHeal> DeepHeal for Vec<T> {
+    #[inline]
+    fn deep_heal(&mut self) { self[..].deep_heal() }
+}
+
+impl<T: DeepHeal> DeepHeal for VecDeque<T> {
+    #[inline]
+    fn deep_heal(&mut self) {
+        for val in self { val.deep_heal() }
+    }
+}
+
+macro_rules! deep_heal_noop {
+    ($($ty:ty),*) => {
+        $(
+            impl DeepHeal for $ty {
+                #[inline]
+                fn deep_heal(&mut self) {}
+            }
+        )*
+    }
+}
+
+deep_heal_noop!((), bool, char, u8, u16, u32, u64, u128, usize,
+                i8, i16, i32, i64, i128, isize, f32, f64, String);
+
+macro_rules! deep_heal_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: DeepHeal),*> DeepHeal for ($($name,)*) {
+            #[inline]
+            #[allow(non_snake_case)]
+            fn deep_heal(&mut self) {
+                let ($(ref mut $name,)*) = *self;
+                $($name.deep_heal();)*
+            }
+        }
+    }
+}
+
+deep_heal_tuple!(A);
+deep_heal_tuple!(A, B);
+deep_heal_tuple!(A, B, C);
+deep_heal_tuple!(A, B, C, D);
+
+/// An error indicating that a structure is poisoned.
+///
+/// Unlike PoisonError, Poisoned does not carry any data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Poisoned;
+
+impl fmt::Display for Poisoned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("poisoned")
+    }
+}
+
+impl Error for Poisoned {}
+
+/// Discards the data, so `?` converts poisoning from get, lock and std locks
+/// alike into Poisoned.
+impl<T> From<PoisonError<T>> for Poisoned {
+    #[inline]
+    fn from(_: PoisonError<T>) -> Poisoned { Poisoned }
+}
+
+/// An error indicating that a guard is already live, returned by Poison::try_guard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct GuardBusy;
+
+impl fmt::Display for GuardBusy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("guard busy")
+    }
+}
+
+impl Error for GuardBusy {}
+
+/// An error indicating that an index was out of bounds, returned by Poison::lock_index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutOfBounds {
+    /// The index which was out of bounds.
+    pub index: usize,
+
+    /// The length of the collection.
+    pub len: usize
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "index {} out of bounds for length {}", self.index, self.len)
+    }
+}
+
+impl Error for OutOfBounds {}
+
+/// The error returned by Poison::try_with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryWithError<E> {
+    /// The Poison was already poisoned, so the closure was not run.
+    Poisoned,
+
+    /// The closure returned an error.
+    Inner(E)
+}
+
+impl<E: fmt::Display> fmt::Display for TryWithError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryWithError::Poisoned => f.write_str("poisoned"),
+            TryWithError::Inner(ref e) => e.fmt(f)
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for TryWithError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            TryWithError::Poisoned => None,
+            TryWithError::Inner(ref e) => Some(e)
+        }
+    }
+}
+
+/// Collections which can be split in two at an index.
+///
+/// Abstracts over `Vec::split_off`, `String::split_off` and similar methods
+/// for use with Poison::split_off.
+pub trait SplitOff {
+    /// The type of the split off tail.
+    type Output;
+
+    /// Split the collection at the given index, returning the tail.
+    fn split_off(&mut self, at: usize) -> Self::Output;
+}
+
+impl<T> SplitOff for Vec<T> {
+    type Output = Vec<T>;
+
+    #[inline]
+    fn split_off(&mut self, at: usize) -> Vec<T> { Vec::split_off(self, at) }
+}
+
+impl SplitOff for String {
+    type Output = String;
+
+    #[inline]
+    fn split_off(&mut self, at: usize) -> String { String::split_off(self, at) }
+}
+
+impl<T> SplitOff for VecDeque<T> {
+    type Output = VecDeque<T>;
+
+    #[inline]
+    fn split_off(&mut self, at: usize) -> VecDeque<T> { VecDeque::split_off(self, at) }
+}
+
+/// Collections whose excess capacity can be released, see Poison::shrink_to_fit.
+pub trait ShrinkToFit {
+    /// Shrink the capacity of the collection as much as possible.
+    fn shrink_to_fit(&mut self);
+}
+
+impl<T> ShrinkToFit for Vec<T> {
+    #[inline]
+    fn shrink_to_fit(&mut self) { Vec::shrink_to_fit(self) }
+}
+
+impl ShrinkToFit for String {
+    #[inline]
+    fn shrink_to_fit(&mut self) { String::shrink_to_fit(self) }
+}
+
+impl<T> ShrinkToFit for VecDeque<T> {
+    #[inline]
+    fn shrink_to_fit(&mut self) { VecDeque::shrink_to_fit(self) }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> ShrinkToFit for HashMap<K, V, S> {
+    #[inline]
+    fn shrink_to_fit(&mut self) { HashMap::shrink_to_fit(self) }
+}
+
+/// A raw poisoning primitive, can be used to build automatically poisoning structures.
+#[derive(Debug)]
+pub struct RawPoison {
+    poisoned: bool,
+    degraded: bool,
+    frozen: bool,
+    observe_only: bool,
+    // Whether a guard, other than a reborrowed one, is live on this RawPoison.
+    guarded: bool,
+    // The number of would-be poisonings in observe-only mode.
+    observed: usize,
+    on_heal: Option<Callback>,
+    on_guard_duration: Option<Callback<dyn FnMut(Duration) + Send>>,
+    // Whether the last of reasons is the reason for the current poisoning.
+    #[cfg(feature = "reason")]
+    has_reason: bool,
+    #[cfg(feature = "reason")]
+    reasons: Vec<String>,
+    #[cfg(feature = "backtrace")]
+    backtrace: Option<Arc<Backtrace>>,
+    #[cfg(feature = "timestamp")]
+    poisoned_at: Option<Instant>,
+    #[cfg(feature = "audit")]
+    history: Vec<PoisonTransition>
+}
+
+/// A snapshot of the diagnostic state of a RawPoison, created by debug_state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoisonState {
+    /// Whether the RawPoison is poisoned.
+    pub poisoned: bool,
+
+    /// Whether the RawPoison is degraded.
+    pub degraded: bool,
+
+    /// Whether the RawPoison is frozen.
+    pub frozen: bool,
+
+    /// The number of would-be poisonings in observe-only mode.
+    pub observed_poisonings: usize,
+
+    /// The message of the panic which poisoned the RawPoison, if recorded.
+    #[cfg(feature = "reason")]
+    pub reason: Option<String>,
+
+    /// How long the RawPoison has been poisoned for.
+    #[cfg(feature = "timestamp")]
+    pub poisoned_for: Option<Duration>,
+
+    /// The number of poisonings in the recorded history.
+    #[cfg(feature = "audit")]
+    pub recent_poisonings: usize
+}
+
+/// The number of reasons kept by a RawPoison, see RawPoison::poison_reasons.
+#[cfg(feature = "reason")]
+pub const REASON_CAPACITY: usize = 16;
+
+/// The number of transitions kept in the history of a RawPoison.
+#[cfg(feature = "audit")]
+pub const AUDIT_CAPACITY: usize = 16;
+
+/// A poison state transition, recorded with the `audit` feature.
+#[cfg(feature = "audit")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoisonTransition {
+    /// The structure became poisoned at the given time.
+    Poisoned(Instant),
+
+    /// The structure was healed at the given time.
+    Healed(Instant)
+}
+
+#[cfg(feature = "audit")]
+impl PoisonTransition {
+    /// Get the time of the transition.
+    pub fn at(&self) -> Instant {
+        match *self {
+            PoisonTransition::Poisoned(at) | PoisonTransition::Healed(at) => at
+        }
+    }
+}
+
+// A callback stored on a RawPoison.
+struct Callback<F: ?Sized = dyn FnMut() + Send>(Box<F>);
+
+// SAFETY: a shared reference to a Callback gives no access to the closure.
+// The closure is only called from methods taking `&mut RawPoison`, or from the
+// drop of the PoisonGuard deferring it, and Debug does not touch it, so two
+// threads can never call it at once. Moving the
+// closure between threads only needs Send, which every callback requires.
+unsafe impl<F: ?Sized> Sync for Callback<F> {}
+
+// RawPoison never reads any state through a callback, so a panic inside one
+// cannot leave the RawPoison inconsistent, and callbacks are never called
+// through a shared reference. Whatever the closure captures belongs to the
+// caller who set it, as with the data in a Poison, which is protected by the
+// poison flag rather than by these bounds. Without these impls, setting a
+// callback field would stop every Poison from being UnwindSafe.
+impl<F: ?Sized> UnwindSafe for Callback<F> {}
+impl<F: ?Sized> RefUnwindSafe for Callback<F> {}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// A guard on a RawPoison.
+///
+/// If the current thread panics before this instance is dropped, the RawPoison
+/// will become poisoned when this instance drops. Guards created by lock_mode
+/// can instead poison unless committed, see PoisonMode.
+#[derive(Debug)]
+pub struct RawPoisonGuard<'poison> {
+    poison: &'poison mut RawPoison,
+    panicking: bool,
+    mode: PoisonMode,
+    committed: bool,
+    armed: bool,
+    deadline: Option<Instant>,
+    #[cfg(any(test, feature = "test-util"))]
+    forced: bool,
+    // Whether this guard marks its RawPoison as guarded.
+    tracked: bool,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span
+}
+
+/// When a guard poisons its associated poison as it drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoisonMode {
+    /// Poison if the current thread panics while the guard is live.
+    ///
+    /// This is the mode used by `lock`.
+    #[default]
+    OnPanic,
+
+    /// Poison whenever the guard is dropped without first being committed,
+    /// whether or not the current thread is panicking.
+    OnDropUnlessCommit
+}
+
+impl RawPoison {
+    /// Create a new RawPoison in a non-poisoned state.
+    #[inline]
+    pub fn new() -> RawPoison {
+        RawPoison {
+            poisoned: false,
+            degraded: false,
+            frozen: false,
+            observe_only: false,
+            guarded: false,
+            observed: 0,
+            on_heal: None,
+            on_guard_duration: None,
+            #[cfg(feature = "reason")]
+            has_reason: false,
+            #[cfg(feature = "reason")]
+            reasons: Vec::new(),
+            #[cfg(feature = "backtrace")]
+            backtrace: None,
+            #[cfg(feature = "timestamp")]
+            poisoned_at: None,
+            #[cfg(feature = "audit")]
+            history: Vec::new()
+        }
+    }
+
+    /// Create a new RawPoison which is already poisoned.
+    #[inline]
+    pub fn poisoned() -> RawPoison {
+        let mut raw = RawPoison::new();
+        raw.poisoned = true;
+
+        #[cfg(feature = "timestamp")]
+        { raw.poisoned_at = Some(Instant::now()); }
+
+        raw
+    }
+
+    /// Check if the RawPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Mark the RawPoison as degraded.
+    ///
+    /// A degraded RawPoison is still usable: unlike poisoning, it does not
+    /// cause lock to fail, but guards report it through is_degraded so the
+    /// data can be used with caution. A panic while holding a guard escalates
+    /// it to poisoned as usual, which clears the degraded state. Has no effect
+    /// if the RawPoison is already poisoned.
+    #[inline]
+    pub fn degrade(&mut self) {
+        if !self.poisoned { self.degraded = true; }
+    }
+
+    /// Check if the RawPoison is degraded.
+    #[inline]
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// Set whether the RawPoison is in observe-only mode.
+    ///
+    /// In observe-only mode, anything which would poison the RawPoison, such
+    /// as a panic while holding a guard, is recorded but the RawPoison is not
+    /// actually poisoned, so operations keep succeeding. Would-be poisonings
+    /// are counted by observed_poisonings, and with the `metrics` and
+    /// `tracing` features, also counted as `poison.observed` and logged as
+    /// warnings. Unlike freezing, which ignores poisoning, observe-only mode
+    /// leaves a record of it. Useful for observing where
+    /// poisoning would happen before rolling it out in existing code. Does not
+    /// affect a RawPoison which is already poisoned.
+    #[inline]
+    pub fn set_observe_only(&mut self, observe_only: bool) {
+        self.observe_only = observe_only;
+    }
+
+    /// Check if the RawPoison is in observe-only mode.
+    #[inline]
+    pub fn is_observe_only(&self) -> bool {
+        self.observe_only
+    }
+
+    /// Get the number of times this RawPoison would have been poisoned while
+    /// in observe-only mode.
+    #[inline]
+    pub fn observed_poisonings(&self) -> usize {
+        self.observed
+    }
+
+    /// Heal the RawPoison if it is poisoned.
+    ///
+    /// Also clears the degraded state.
+    #[inline]
+    pub fn heal(&mut self) {
+        self.degraded = false;
+
+        if self.poisoned {
+            self.poisoned = false;
+
+            #[cfg(feature = "reason")]
+            { self.has_reason = false; }
+
+            #[cfg(feature = "backtrace")]
+            { self.backtrace = None; }
+
+            #[cfg(feature = "timestamp")]
+            { self.poisoned_at = None; }
+
+            #[cfg(feature = "audit")]
+            self.record(PoisonTransition::Healed(Instant::now()));
+
+            #[cfg(feature = "metrics")]
+            metrics::counter!("poison.healed").increment(1);
+
+            #[cfg(feature = "tracing")]
+            tracing::info!(target: "poison", "healed");
+
+            if let Some(Callback(ref mut on_heal)) = self.on_heal {
+                on_heal();
+            }
+        }
+    }
+
+    /// Set a callback to run whenever the RawPoison heals.
+    ///
+    /// The callback only runs when healing actually transitions the RawPoison
+    /// from poisoned to healthy, not when healing an already healthy
+    /// RawPoison. Replaces any previously set callback.
+    #[inline]
+    pub fn set_on_heal<F: FnMut() + Send + 'static>(&mut self, f: F) {
+        self.on_heal = Some(Callback(Box::new(f)));
+    }
+
+    /// Set a callback to run with the time each timed guard was held for.
+    ///
+    /// Only guards created through Poison::lock_timed are timed. Replaces any
+    /// previously set callback.
+    #[inline]
+    pub fn set_on_guard_duration<F: FnMut(Duration) + Send + 'static>(&mut self, f: F) {
+        self.on_guard_duration = Some(Callback(Box::new(f)));
+    }
+
+    /// Get a poison lock on this RawPoison.
+    ///
+    /// If the RawPoison is already poisoned, returns PoisonError.
+    #[inline(always)]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock(&mut self) -> LockResult<RawPoisonGuard<'_>> {
+        self.lock_mode(PoisonMode::OnPanic)
+    }
+
+    /// Get a poison lock on this RawPoison which poisons according to `mode`.
+    ///
+    /// If the RawPoison is already poisoned, returns PoisonError.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_mode(&mut self, mode: PoisonMode) -> LockResult<RawPoisonGuard<'_>> {
+        let poisoned = self.poisoned;
+
+        let guard = RawPoisonGuard::new(self, mode);
+
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Get a poison lock on this RawPoison with a deadline.
+    ///
+    /// Acts as a watchdog: in addition to poisoning on panic, the guard will
+    /// poison the RawPoison if it is dropped after `deadline` has passed,
+    /// indicating a stuck or overlong operation.
+    ///
+    /// If the RawPoison is already poisoned, returns PoisonError.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn lock_deadline(&mut self, deadline: Instant) -> LockResult<RawPoisonGuard<'_>> {
+        map_result(self.lock(), |mut guard| {
+            guard.deadline = Some(deadline);
+            guard
+        })
+    }
+
+    /// Attach poison-on-panic behavior to an arbitrary value.
+    ///
+    /// If the current thread panics before the returned PoisonOn is dropped,
+    /// this RawPoison will become poisoned, before the value itself is dropped.
+    /// Useful for retrofitting poisoning onto guard types from other libraries.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn attach<G>(&mut self, value: G) -> PoisonOn<'_, G> {
+        PoisonOn {
+            guard: RawPoisonGuard::new(self, PoisonMode::OnPanic),
+            value
+        }
+    }
+
+    /// Freeze the RawPoison, so guards will not poison it when dropped.
+    ///
+    /// While frozen, locking still works as usual, but panics and uncommitted
+    /// guards do not poison. Useful for controlled operations where transient
+    /// panics are expected.
+    #[inline]
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Thaw a frozen RawPoison, so guards will poison it again.
+    #[inline]
+    pub fn thaw(&mut self) {
+        self.frozen = false;
+    }
+
+    /// Check if the RawPoison is frozen.
+    #[inline]
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Get the number of RawPoisonGuards currently live on this thread.
+    ///
+    /// Includes the guards held by PoisonGuards and PoisonOns. Useful for
+    /// diagnostics, for instance to check that no guard has been leaked: a
+    /// leaked guard is never dropped, so it stays counted until the thread
+    /// exits. With the `backtrace` feature, panics on a thread with a leaked
+    /// guard keep capturing backtraces.
+    #[inline]
+    pub fn current_guard_depth() -> usize {
+        hook::depth()
+    }
+
+    /// Get the message of the panic which poisoned this RawPoison.
+    ///
+    /// Recorded by Poison::scope_capturing. Returns None if the RawPoison is
+    /// not poisoned, or was poisoned some other way.
+    #[cfg(feature = "reason")]
+    #[inline]
+    pub fn poison_reason(&self) -> Option<&str> {
+        if self.has_reason { self.reasons.last().map(String::as_str) } else { None }
+    }
+
+    /// Get the reasons this RawPoison has been poisoned for, oldest first.
+    ///
+    /// Unlike poison_reason, healing does not clear these, so a RawPoison
+    /// which is poisoned repeatedly keeps the reason for each time. At most
+    /// the last REASON_CAPACITY reasons are kept. Cleared by reset.
+    #[cfg(feature = "reason")]
+    #[inline]
+    pub fn poison_reasons(&self) -> &[String] {
+        &self.reasons
+    }
+
+    /// Fully reset the RawPoison, healing it and clearing its recorded
+    /// reasons and history, with the `reason` and `audit` features.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.heal();
+
+        #[cfg(feature = "reason")]
+        self.reasons.clear();
+
+        #[cfg(feature = "audit")]
+        self.history.clear();
+    }
+
+    // Take on the poisoned state of `other`, along with the diagnostics
+    // describing it, keeping everything else about this RawPoison. Goes
+    // through poison, so the transition is recorded like any other.
+    #[cfg_attr(not(any(feature = "backtrace", feature = "timestamp", feature = "reason")),
+               allow(unused_variables))]
+    fn inherit_poison(&mut self, other: RawPoison) {
+        self.poison();
+
+        if self.poisoned {
+            #[cfg(feature = "backtrace")]
+            { self.backtrace = other.backtrace; }
+
+            #[cfg(feature = "timestamp")]
+            { self.poisoned_at = other.poisoned_at; }
+
+            #[cfg(feature = "reason")]
+            if other.has_reason {
+                if let Some(reason) = other.reasons.into_iter().last() { self.set_reason(reason); }
+            }
+        }
+    }
+
+    // Record the reason the RawPoison is poisoned, evicting the oldest if full.
+    #[cfg(feature = "reason")]
+    fn set_reason(&mut self, reason: String) {
+        if self.reasons.len() == REASON_CAPACITY {
+            self.reasons.remove(0);
+        }
+
+        self.reasons.push(reason);
+        self.has_reason = true;
+    }
+
+    /// Get the backtrace of the panic which poisoned this RawPoison.
+    ///
+    /// Only available with the `backtrace` feature. Returns None if the
+    /// RawPoison is not poisoned, or was not poisoned by a panic on a thread
+    /// holding a guard.
+    #[cfg(feature = "backtrace")]
+    #[inline]
+    pub fn poison_backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_deref()
+    }
+
+    /// Get the time at which this RawPoison became poisoned.
+    ///
+    /// Only available with the `timestamp` feature. Returns None if the
+    /// RawPoison is not poisoned.
+    #[cfg(feature = "timestamp")]
+    #[inline]
+    pub fn poisoned_since(&self) -> Option<Instant> {
+        self.poisoned_at
+    }
+
+    /// Heal the RawPoison if it has been poisoned for at least `dur`.
+    ///
+    /// Only available with the `timestamp` feature. Returns whether the
+    /// RawPoison was healed.
+    ///
+    /// This is an ordinary heal, so it runs the on_heal callback. Freezing does
+    /// not prevent it, since freeze only stops guards from poisoning. There is
+    /// no way to fuse a RawPoison against healing, so callers which must not
+    /// auto-heal a structure should not call this on it.
+    #[cfg(feature = "timestamp")]
+    #[inline]
+    pub fn heal_if_older_than(&mut self, dur: Duration) -> bool {
+        match self.poisoned_at {
+            Some(at) if at.elapsed() >= dur => {
+                self.heal();
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Get a snapshot of all of the diagnostic state of this RawPoison.
+    ///
+    /// Aggregates the state reported by the individual getters, including
+    /// whichever fields the enabled features provide.
+    pub fn debug_state(&self) -> PoisonState {
+        PoisonState {
+            poisoned: self.poisoned,
+            degraded: self.degraded,
+            frozen: self.frozen,
+            observed_poisonings: self.observed,
+            #[cfg(feature = "reason")]
+            reason: self.poison_reason().map(String::from),
+            #[cfg(feature = "timestamp")]
+            poisoned_for: self.poisoned_at.map(|at| at.elapsed()),
+            #[cfg(feature = "audit")]
+            recent_poisonings: self.history.iter()
+                .filter(|transition| matches!(transition, PoisonTransition::Poisoned(_)))
+                .count()
+        }
+    }
+
+    /// Get the most recent transitions of this RawPoison, oldest first.
+    ///
+    /// Only available with the `audit` feature. At most the last
+    /// AUDIT_CAPACITY transitions are kept.
+    #[cfg(feature = "audit")]
+    #[inline]
+    pub fn history(&self) -> &[PoisonTransition] {
+        &self.history
+    }
+
+    // Mark the RawPoison as poisoned, recording the transition.
+    #[inline]
+    fn poison(&mut self) {
+        if self.observe_only && !self.poisoned {
+            self.observed += 1;
+
+            #[cfg(feature = "metrics")]
+            metrics::counter!("poison.observed").increment(1);
+
+            #[cfg(feature = "tracing")]
+            tracing::warn!(target: "poison", "would have poisoned");
+        } else if !self.poisoned {
+            self.poisoned = true;
+            self.degraded = false;
+
+            #[cfg(feature = "timestamp")]
+            { self.poisoned_at = Some(Instant::now()); }
+
+            #[cfg(feature = "audit")]
+            self.record(PoisonTransition::Poisoned(Instant::now()));
+
+            #[cfg(feature = "metrics")]
+            metrics::counter!("poison.poisoned").increment(1);
+
+            #[cfg(feature = "tracing")]
+            tracing::error!(target: "poison", "poisoned");
+        }
+    }
+
+    // Record a transition in the history, evicting the oldest if full.
+    #[cfg(feature = "audit")]
+    fn record(&mut self, transition: PoisonTransition) {
+        if self.history.len() == AUDIT_CAPACITY {
+            self.history.rotate_left(1);
+            self.history[AUDIT_CAPACITY - 1] = transition;
+        } else {
+            self.history.push(transition);
+        }
+    }
+}
+
+impl Default for RawPoison {
+    #[inline]
+    fn default() -> RawPoison { RawPoison::new() }
+}
+
+/// An armed scope on an associated Poison, created by Poison::arm_scope.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct PoisonArmScope<'poison, T: ?Sized + 'poison> {
+    guard: PoisonGuard<'poison, T>,
+    was_frozen: bool
+}
+
+impl<'poison, T: ?Sized> PoisonArmScope<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+}
+
+impl<'poison, T: ?Sized> Drop for PoisonArmScope<'poison, T> {
+    fn drop(&mut self) {
+        // Poison before restoring the frozen state, which would prevent it.
+        self.guard.guard.settle();
+        self.guard.guard.poison.frozen = self.was_frozen;
+    }
+}
+
+/// A guard over two disjoint halves of a slice, created by Poison::split_at_guard.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct PoisonSplitGuard<'poison, E: 'poison> {
+    left: &'poison mut [E],
+    right: &'poison mut [E],
+    guard: RawPoisonGuard<'poison>
+}
+
+impl<'poison, E> PoisonSplitGuard<'poison, E> {
+    /// Get immutable references to both halves.
+    pub fn get(&self) -> (&[E], &[E]) { (self.left, self.right) }
+
+    /// Get mutable references to both halves.
+    pub fn get_mut(&mut self) -> (&mut [E], &mut [E]) { (self.left, self.right) }
+
+    /// Get the raw poison guard shared by both halves.
+    pub fn into_raw(self) -> RawPoisonGuard<'poison> { self.guard }
+}
+
+/// A copy of the data and poison state of a Poison, created by Poison::snapshot.
+#[derive(Debug, Clone)]
+pub struct PoisonSnapshot<T> {
+    data: T,
+    poisoned: bool
+}
+
+impl<T> PoisonSnapshot<T> {
+    /// Get an immutable reference to the captured data.
+    pub fn get(&self) -> &T { &self.data }
+
+    /// Check if the Poison was poisoned when the snapshot was taken.
+    pub fn is_poisoned(&self) -> bool { self.poisoned }
+}
+
+/// A combined guard on a Mutex and the Poison inside it, created by Poison::lock_arc.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct PoisonMutexGuard<'a, T: 'a> {
+    guard: MutexGuard<'a, Poison<T>>,
+    panicking: bool
+}
+
+impl<'a, T> PoisonMutexGuard<'a, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { &self.guard.data }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { &mut self.guard.data }
+}
+
+impl<'a, T> Drop for PoisonMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Settle through a raw guard so poisoning behaves exactly as for lock.
+        let mut raw = RawPoisonGuard::new(&mut self.guard.raw, PoisonMode::OnPanic);
+        raw.panicking = self.panicking;
+        drop(raw);
+
+        hook::exit();
+    }
+}
+
+/// An async operation holding a poison lock, created by Poison::with_async.
+///
+/// If this future is dropped before it completes, the Poison will become
+/// poisoned.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct WithAsync<'poison, Fut> {
+    // Structurally pinned: never moved out of a pinned WithAsync.
+    fut: Fut,
+    poison: &'poison mut RawPoison,
+    poisoned: bool,
+    done: bool
+}
+
+#[cfg(feature = "async")]
+impl<'poison, Fut: Future> Future for WithAsync<'poison, Fut> {
+    type Output = LockResult<Fut::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Only fut is pinned, and it is never moved, so projecting is sound.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+
+        fut.poll(cx).map(|value| {
+            this.done = true;
+            if this.poisoned { Err(PoisonError::new(value)) } else { Ok(value) }
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'poison, Fut> Drop for WithAsync<'poison, Fut> {
+    fn drop(&mut self) {
+        if !self.done && !self.poison.frozen { self.poison.poison(); }
+    }
+}
+
+/// A guard which measures how long it is held, created by Poison::lock_timed.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct TimedPoisonGuard<'poison, T: ?Sized + 'poison> {
+    guard: PoisonGuard<'poison, T>,
+    start: Instant
+}
+
+impl<'poison, T: ?Sized> TimedPoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+
+    /// Get the time this guard has been held for so far.
+    pub fn elapsed(&self) -> Duration { self.start.elapsed() }
+}
+
+impl<'poison, T: ?Sized> Drop for TimedPoisonGuard<'poison, T> {
+    fn drop(&mut self) {
+        let elapsed = self.elapsed();
+
+        if let Some(Callback(ref mut on_guard_duration)) = self.guard.guard.poison.on_guard_duration {
+            on_guard_duration(elapsed);
+        }
+    }
+}
+
+/// A guard which checks an invariant when dropped, created by Poison::lock_checked.
+pub struct CheckedPoisonGuard<'poison, T: ?Sized + 'poison, F: Fn(&T) -> bool> {
+    guard: PoisonGuard<'poison, T>,
+    inv: F
+}
+
+impl<'poison, T: ?Sized, F: Fn(&T) -> bool> CheckedPoisonGuard<'poison, T, F> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+}
+
+impl<'poison, T: ?Sized, F: Fn(&T) -> bool> Drop for CheckedPoisonGuard<'poison, T, F> {
+    fn drop(&mut self) {
+        let raw = &self.guard.guard;
+        let poison = raw.armed && !raw.poison.frozen && !thread::panicking()
+            && !(self.inv)(self.guard.data);
+
+        if poison { self.guard.guard.poison.poison(); }
+    }
+}
+
+impl<'poison, T: ?Sized + fmt::Debug, F: Fn(&T) -> bool> fmt::Debug for CheckedPoisonGuard<'poison, T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CheckedPoisonGuard").field("guard", &self.guard).finish()
+    }
+}
+
+/// A guard which checks an invariant on a sample of drops, created by
+/// Poison::lock_sampled.
+pub struct SampledPoisonGuard<'poison, T: ?Sized + 'poison, F: Fn(&T) -> bool> {
+    guard: PoisonGuard<'poison, T>,
+    rate: f64,
+    inv: F
+}
+
+impl<'poison, T: ?Sized, F: Fn(&T) -> bool> SampledPoisonGuard<'poison, T, F> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+}
+
+impl<'poison, T: ?Sized, F: Fn(&T) -> bool> Drop for SampledPoisonGuard<'poison, T, F> {
+    fn drop(&mut self) {
+        let raw = &self.guard.guard;
+        let poison = raw.armed && !raw.poison.frozen && !thread::panicking()
+            && sample::sample(self.rate) && !(self.inv)(self.guard.data);
+
+        if poison { self.guard.guard.poison.poison(); }
+    }
+}
+
+impl<'poison, T: ?Sized + fmt::Debug, F: Fn(&T) -> bool> fmt::Debug for SampledPoisonGuard<'poison, T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SampledPoisonGuard")
+            .field("guard", &self.guard)
+            .field("rate", &self.rate)
+            .finish()
+    }
+}
+
+/// A guard which restores the data on panic, created by Poison::lock_rollback.
+#[derive(Debug)]
+pub struct RollbackPoisonGuard<'poison, T: Clone + 'poison> {
+    guard: PoisonGuard<'poison, T>,
+    snapshot: Option<T>
+}
+
+impl<'poison, T: Clone> RollbackPoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+}
+
+impl<'poison, T: Clone> Drop for RollbackPoisonGuard<'poison, T> {
+    fn drop(&mut self) {
+        let raw = &self.guard.guard;
+        if raw.armed && !raw.panicking && thread::panicking() {
+            if let Some(snapshot) = self.snapshot.take() { *self.guard.data = snapshot; }
+        }
+    }
+}
+
+/// A guard which poisons on panic or cancellation, created by Poison::lock_cancellable.
+#[derive(Debug)]
+pub struct CancellablePoisonGuard<'poison, T: ?Sized + 'poison> {
+    guard: PoisonGuard<'poison, T>,
+    token: Arc<AtomicBool>
+}
+
+impl<'poison, T: ?Sized> CancellablePoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+
+    /// Check if the cancellation token has been set.
+    pub fn is_cancelled(&self) -> bool { self.token.load(std::sync::atomic::Ordering::Acquire) }
+}
+
+impl<'poison, T: ?Sized> Drop for CancellablePoisonGuard<'poison, T> {
+    fn drop(&mut self) {
+        let cancelled = self.is_cancelled();
+        let raw = &mut self.guard.guard;
+        let poison = cancelled && raw.armed && !raw.poison.frozen;
+
+        // Settle first, so a concurrent panic still records its backtrace.
+        raw.settle();
+        if poison { raw.poison.poison(); }
+    }
+}
+
+/// An owning combined guard on a shared Poison, created by Poison::lock_owned.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+#[derive(Debug)]
+pub struct OwnedPoisonGuard<T: 'static> {
+    // Must be dropped before the Arc it borrows from.
+    guard: PoisonMutexGuard<'static, T>,
+    _mutex: Arc<Mutex<Poison<T>>>
+}
+
+impl<T> OwnedPoisonGuard<T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.guard.get() }
+
+    /// Get a mutable reference to the data.
+    pub fn get_mut(&mut self) -> &mut T { self.guard.get_mut() }
+}
+
+/// A guard accessing the data through a projection, created by Poison::lock_as.
+///
+/// If the current thread panics before this instance is dropped, the Poison
+/// will become poisoned when this instance drops.
+pub struct MappedPoisonGuard<'poison, T: ?Sized + 'poison, U: ?Sized, F> {
+    guard: PoisonGuard<'poison, T>,
+    map: F,
+    _marker: PhantomData<fn(&mut T) -> &mut U>
+}
+
+impl<'poison, T: ?Sized, U: ?Sized, F> MappedPoisonGuard<'poison, T, U, F>
+    where F: Fn(&mut T) -> &mut U {
+    /// Get a mutable reference to the projected data.
+    pub fn get_mut(&mut self) -> &mut U { (self.map)(self.guard.get_mut()) }
+
+    /// Get the underlying guard, discarding the projection.
+    pub fn into_guard(self) -> PoisonGuard<'poison, T> { self.guard }
+}
+
+impl<'poison, T: ?Sized, U: ?Sized, F> fmt::Debug for MappedPoisonGuard<'poison, T, U, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MappedPoisonGuard")
+    }
+}
+
+/// An operation budget for a closure run by Poison::with_budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budget {
+    used: u64,
+    max: u64
+}
+
+impl Budget {
+    /// Charge `n` operations to the budget.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the total charged exceeds the budget.
+    #[inline]
+    pub fn charge(&mut self, n: u64) {
+        self.used = self.used.saturating_add(n);
+
+        if self.used > self.max {
+            panic!("operation budget of {} exceeded", self.max);
+        }
+    }
+
+    /// Get the number of operations charged so far.
+    #[inline]
+    pub fn used(&self) -> u64 { self.used }
+
+    /// Get the number of operations left in the budget.
+    #[inline]
+    pub fn remaining(&self) -> u64 { self.max.saturating_sub(self.used) }
+}
+
+/// A reusable operation over poisonable data, applied by Poison::apply.
+pub struct PoisonOp<T: ?Sized, Args, R> {
+    f: Box<OpFn<T, Args, R>>
+}
+
+// The function run by a PoisonOp.
+type OpFn<T, Args, R> = dyn Fn(&mut T, Args) -> R;
+
+impl<T: ?Sized, Args, R> PoisonOp<T, Args, R> {
+    /// Create a new PoisonOp from the function it runs.
+    #[inline]
+    pub fn new<F: Fn(&mut T, Args) -> R + 'static>(f: F) -> PoisonOp<T, Args, R> {
+        PoisonOp { f: Box::new(f) }
+    }
+}
+
+impl<T: ?Sized, Args, R> fmt::Debug for PoisonOp<T, Args, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PoisonOp")
+    }
+}
+
+/// A boxed mutation which can be queued and later run by Poison::run_command.
+pub type PoisonCommand<T> = Box<dyn FnOnce(&mut T)>;
+
+/// A sequence of mutations under a single guard, created by Poison::batch.
+#[derive(Debug)]
+pub struct PoisonBatch<'poison, T: ?Sized + 'poison> {
+    guard: PoisonGuard<'poison, T>
+}
+
+impl<'poison, T: ?Sized> PoisonBatch<'poison, T> {
+    /// Run a step of the batch over the data.
+    pub fn step<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        f(self.guard.get_mut())
+    }
+}
+
+/// A read-only guard on an associated Poison, created by PoisonGuard::downgrade.
+///
+/// Unlike PoisonGuard, this guard never poisons the Poison when dropped,
+/// since it cannot be used to mutate the data.
+#[derive(Debug)]
+pub struct PoisonReadGuard<'poison, T: ?Sized + 'poison> {
+    data: &'poison mut T,
+    // Disarmed, held to keep the logical lock until upgraded.
+    guard: RawPoisonGuard<'poison>
+}
+
+impl<'poison, T: ?Sized> PoisonReadGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { self.data }
+
+    /// Upgrade this guard back to a mutable guard.
+    ///
+    /// The logical lock is held throughout. The inverse of downgrade: the
+    /// returned guard poisons again when dropped, according to the mode of
+    /// the guard this was downgraded from, while the read guard never does.
+    pub fn upgrade(mut self) -> PoisonGuard<'poison, T> {
+        self.guard.rearm();
+        PoisonGuard { data: self.data, guard: self.guard, deferred: None }
+    }
+}
+
+/// A guarded entry in a poisonable map, created by Poison::entry.
+///
+/// If the current thread panics before this instance, or a guard created
+/// from it, is dropped, the Poison will become poisoned.
+#[derive(Debug)]
+pub struct PoisonEntry<'poison, K: 'poison, V: 'poison> {
+    entry: Entry<'poison, K, V>,
+    guard: RawPoisonGuard<'poison>
+}
+
+impl<'poison, K, V> PoisonEntry<'poison, K, V> {
+    /// Get the key of this entry.
+    pub fn key(&self) -> &K { self.entry.key() }
+
+    /// Modify the value of an occupied entry in place.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        PoisonEntry { entry: self.entry.and_modify(f), guard: self.guard }
+    }
+
+    /// Insert `default` if the entry is vacant, then get a guard on the value.
+    pub fn or_insert(self, default: V) -> PoisonGuard<'poison, V> {
+        PoisonGuard { data: self.entry.or_insert(default), guard: self.guard, deferred: None }
+    }
+
+    /// Insert the result of `default` if the entry is vacant, then get a
+    /// guard on the value.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> PoisonGuard<'poison, V> {
+        PoisonGuard { data: self.entry.or_insert_with(default), guard: self.guard, deferred: None }
+    }
+
+    /// Insert the default value if the entry is vacant, then get a guard on
+    /// the value.
+    pub fn or_default(self) -> PoisonGuard<'poison, V> where V: Default {
+        PoisonGuard { data: self.entry.or_default(), guard: self.guard, deferred: None }
+    }
+}
+
+/// A value with poison-on-panic behavior attached, created by RawPoison::attach.
+///
+/// If the current thread panics before this instance is dropped, the RawPoison
+/// will become poisoned when this instance drops. The poisoning is applied
+/// before the wrapped value is dropped.
+#[derive(Debug)]
+pub struct PoisonOn<'poison, G> {
+    // Fields are dropped in declaration order, so guard must come first.
+    guard: RawPoisonGuard<'poison>,
+    value: G
+}
+
+impl<'poison, G> PoisonOn<'poison, G> {
+    /// Get an immutable reference to the wrapped value.
+    pub fn get(&self) -> &G { &self.value }
+
+    /// Get a mutable reference to the wrapped value.
+    pub fn get_mut(&mut self) -> &mut G { &mut self.value }
+
+    /// Detach the wrapped value, applying the usual poisoning check first.
+    pub fn into_inner(self) -> G {
+        drop(self.guard);
+        self.value
+    }
+}
+
+impl<'poison> RawPoisonGuard<'poison> {
+    /// Reborrow this guard for a shorter lifetime.
+    ///
+    /// See PoisonGuard::reborrow for how poisoning interacts between the
+    /// reborrowed guard and this guard. The reborrowed guard always uses
+    /// PoisonMode::OnPanic, so committing remains the job of this guard.
+    #[cfg_attr(feature = "tracing", track_caller)]
+    pub fn reborrow(&mut self) -> RawPoisonGuard<'_> {
+        RawPoisonGuard::new_tracked(&mut *self.poison, PoisonMode::OnPanic, false)
+    }
+
+    /// Get the mode this guard poisons in.
+    pub fn mode(&self) -> PoisonMode { self.mode }
+
+    /// Check if the associated RawPoison is degraded.
+    pub fn is_degraded(&self) -> bool { self.poison.degraded }
+
+    /// Make the guard poison when dropped, as if the thread were panicking.
+    ///
+    /// Only available with the `test-util` feature, to exercise poisoning
+    /// deterministically in tests. A frozen RawPoison is still not poisoned.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn force_poison_on_drop(&mut self) {
+        self.forced = true;
+    }
+
+    /// Get the time left before this guard's deadline.
+    ///
+    /// Returns None if the guard has no deadline, and zero if the deadline
+    /// has already passed.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Commit the guard, so it will not poison when dropped.
+    ///
+    /// Only has an effect on guards using PoisonMode::OnDropUnlessCommit.
+    /// Returns whether this call committed the guard, so false if the guard
+    /// was already committed or uses another mode.
+    pub fn commit(&mut self) -> bool {
+        let committed = self.mode == PoisonMode::OnDropUnlessCommit && !self.committed;
+        if committed { self.committed = true; }
+        committed
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    fn new(poison: &'poison mut RawPoison, mode: PoisonMode) -> RawPoisonGuard<'poison> {
+        RawPoisonGuard::new_tracked(poison, mode, true)
+    }
+
+    // Create a guard, which marks the RawPoison as guarded if `tracked`, and
+    // with the `reentrancy-check` feature, panics if it already was. Reborrowed
+    // guards are not tracked, since they are nested inside a live guard by
+    // design.
+    #[inline]
+    #[cfg_attr(feature = "tracing", track_caller)]
+    fn new_tracked(poison: &'poison mut RawPoison, mode: PoisonMode, tracked: bool)
+                   -> RawPoisonGuard<'poison> {
+        #[cfg(all(debug_assertions, feature = "reentrancy-check"))]
+        {
+            if tracked && poison.guarded && !thread::panicking() {
+                panic!("reentrant lock detected: this Poison already has a live or leaked guard");
+            }
+        }
+        if tracked { poison.guarded = true; }
+
+        hook::enter();
+
+        RawPoisonGuard {
+            poison,
+            panicking: thread::panicking(),
+            mode,
+            committed: false,
+            armed: true,
+            deadline: None,
+            #[cfg(any(test, feature = "test-util"))]
+            forced: false,
+            tracked,
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(target: "poison", "guard", location = %Location::caller())
+        }
+    }
+
+    // Stop the guard from poisoning when dropped.
+    #[inline]
+    fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    // Make a disarmed guard poison when dropped again, treating any panic
+    // already in progress as predating it.
+    #[inline]
+    fn rearm(&mut self) {
+        self.armed = true;
+        self.panicking = thread::panicking();
+    }
+}
+
+impl<'poison> Drop for RawPoisonGuard<'poison> {
+    #[inline]
+    fn drop(&mut self) {
+        self.settle();
+        hook::exit();
+
+        if self.tracked { self.poison.guarded = false; }
+    }
+}
+
+impl<'poison> RawPoisonGuard<'poison> {
+    // Apply the drop-time poisoning decision now, then disarm the guard.
+    #[inline]
+    fn settle(&mut self) {
+        let panicked = !self.panicking && thread::panicking();
+
+        let overdue = self.deadline.is_some_and(|deadline| Instant::now() > deadline);
+
+        let poison = overdue || match self.mode {
+            PoisonMode::OnPanic => panicked,
+            PoisonMode::OnDropUnlessCommit => !self.committed
+        };
+
+        #[cfg(any(test, feature = "test-util"))]
+        let poison = poison || self.forced;
+
+        if poison && self.armed && !self.poison.frozen {
+            #[cfg(feature = "backtrace")]
+            {
+                if panicked && !self.poison.poisoned && !self.poison.observe_only {
+                    self.poison.backtrace = hook::current();
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            let _entered = self.span.enter();
+
+            self.poison.poison();
+        }
+
+        self.armed = false;
+    }
+}
+
+// Restores the data through `restore` if dropped during a panic.
+//
+// Must be dropped before the associated RawPoisonGuard so the data is
+// restored before the poison is recorded.
+struct Rollback<'a, T: ?Sized + 'a, S: FnOnce(&mut T)> {
+    data: &'a mut T,
+    restore: Option<S>,
+    panicking: bool
+}
+
+impl<'a, T: ?Sized, S: FnOnce(&mut T)> Drop for Rollback<'a, T, S> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            if let Some(restore) = self.restore.take() {
+                restore(self.data);
+            }
+        }
+    }
+}
+
+// Extract the message of a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("non-string panic")
+    }
+}
+
+// Build the Err variant of a LockResult out of line, keeping the healthy
+// path of the accessors which use it small.
+#[cold]
+#[inline(never)]
+fn err_poisoned<T>(val: T) -> LockResult<T> {
+    Err(PoisonError::new(val))
+}
+
+/// A simple, useful combinator for dealing with LockResult.
+///
+/// Applies the action to either the Ok or Err variants
+/// of the LockResult and returns a new LockResult in the same
+/// state with a new value.
+pub fn map_result<T, U, F>(result: LockResult<T>, f: F)
+                           -> LockResult<U>
+                           where F: FnOnce(T) -> U {
+    match result {
+        Ok(t) => Ok(f(t)),
+        Err(e) => Err(PoisonError::new(f(e.into_inner())))
+    }
+}
+
+/// A version of map_result with separate actions for each variant.
+///
+/// Applies `ok` to the value of an Ok variant or `err` to the value of an
+/// Err variant, returning a new LockResult in the same state.
+pub fn map_result_both<T, U, F, G>(result: LockResult<T>, ok: F, err: G)
+                                   -> LockResult<U>
+                                   where F: FnOnce(T) -> U, G: FnOnce(T) -> U {
+    match result {
+        Ok(t) => Ok(ok(t)),
+        Err(e) => Err(PoisonError::new(err(e.into_inner())))
+    }
+}
+
+/// Flatten a nested LockResult into a single LockResult.
+///
+/// The result is poisoned if either layer was poisoned.
+pub fn flatten_result<T>(result: LockResult<LockResult<T>>) -> LockResult<T> {
+    match result {
+        Ok(inner) => inner,
+        Err(e) => Err(PoisonError::new(e.into_inner().unwrap_or_else(PoisonError::into_inner)))
+    }
+}
+
+/// A fallible version of map_result.
+///
+/// Applies the action to either the Ok or Err variants of the LockResult. If
+/// the action fails its error is returned, otherwise a new LockResult in the
+/// same state as the original is returned.
+pub fn try_map_result<T, U, E, F>(result: LockResult<T>, f: F)
+                                  -> Result<LockResult<U>, E>
+                                  where F: FnOnce(T) -> Result<U, E> {
+    match result {
+        Ok(t) => f(t).map(Ok),
+        Err(e) => f(e.into_inner()).map(|u| Err(PoisonError::new(u)))
+    }
+}
+
+/// Project the data of a guarded LockResult.
+///
+/// The guard-aware version of map_result: applies the projection to the data
+/// of the PoisonGuard in either the Ok or Err variant, keeping the same
+/// underlying poison guard and LockResult state.
+pub fn map_lock<'poison, T, U, F>(result: LockResult<PoisonGuard<'poison, T>>, f: F)
+                                  -> LockResult<PoisonGuard<'poison, U>>
+                                  where T: ?Sized, U: ?Sized,
+                                        F: FnOnce(&'poison mut T) -> &'poison mut U {
+    map_result(result, move |guard| {
+        let (data, guard) = guard.into_parts();
+        PoisonGuard { data: f(data), guard, deferred: None }
+    })
+}
+
+/// Lock every Poison in a slice.
+///
+/// Guards are collected for every element even if some are poisoned, in
+/// which case PoisonError containing all of the guards is returned.
+pub fn lock_all<T>(poisons: &mut [Poison<T>]) -> LockResult<Vec<PoisonGuard<'_, T>>> {
+    let mut poisoned = false;
+
+    let guards = poisons.iter_mut().map(|poison| {
+        poison.lock().unwrap_or_else(|err| {
+            poisoned = true;
+            err.into_inner()
+        })
+    }).collect();
+
+    if poisoned { Err(PoisonError::new(guards)) } else { Ok(guards) }
+}
+
+/// Lock two Mutexes containing Poisons in a consistent global order.
+///
+/// The Mutexes are locked in address order, so threads locking overlapping
+/// sets of Mutexes with this function cannot deadlock against each other. The
+/// guards are returned in argument order. See Poison::lock_arc.
+///
+/// # Panics
+///
+/// Panics if the same Mutex is passed more than once.
+pub fn lock_ordered2<'a, T>(a: &'a Mutex<Poison<T>>, b: &'a Mutex<Poison<T>>)
+                            -> (LockResult<PoisonMutexGuard<'a, T>>,
+                                LockResult<PoisonMutexGuard<'a, T>>) {
+    let [a, b] = lock_ordered([a, b]);
+    (a, b)
+}
+
+/// Lock three Mutexes containing Poisons in a consistent global order.
+///
+/// See lock_ordered2.
+///
+/// # Panics
+///
+/// Panics if the same Mutex is passed more than once.
+#[allow(clippy::type_complexity)] // One guard per argument, as in lock_ordered2.
+pub fn lock_ordered3<'a, T>(a: &'a Mutex<Poison<T>>, b: &'a Mutex<Poison<T>>,
+                            c: &'a Mutex<Poison<T>>)
+                            -> (LockResult<PoisonMutexGuard<'a, T>>,
+                                LockResult<PoisonMutexGuard<'a, T>>,
+                                LockResult<PoisonMutexGuard<'a, T>>) {
+    let [a, b, c] = lock_ordered([a, b, c]);
+    (a, b, c)
+}
+
+// Lock each Mutex in address order, returning the guards in argument order.
+fn lock_ordered<'a, T, const N: usize>(mutexes: [&'a Mutex<Poison<T>>; N])
+                                       -> [LockResult<PoisonMutexGuard<'a, T>>; N] {
+    let mut order: [usize; N] = array::from_fn(|i| i);
+    order.sort_by_key(|&i| mutexes[i] as *const Mutex<Poison<T>>);
+    assert!(order.windows(2).all(|w| !ptr::eq(mutexes[w[0]], mutexes[w[1]])),
+            "the same Mutex was passed more than once");
+
+    let mut guards: [Option<_>; N] = array::from_fn(|_| None);
+    for &i in &order { guards[i] = Some(Poison::lock_arc(mutexes[i])); }
+
+    guards.map(|guard| guard.expect("every Mutex is locked"))
+}
+
+/// Collect the data of several Poisons into a single Poison.
+///
+/// The result is poisoned if any of the Poisons were poisoned.
+pub fn collect_poison<T, I: IntoIterator<Item = Poison<T>>>(iter: I) -> Poison<Vec<T>> {
+    let mut poisoned = false;
+
+    let data = iter.into_iter().map(|poison| {
+        poison.into_inner().unwrap_or_else(|err| {
+            poisoned = true;
+            err.into_inner()
+        })
+    }).collect();
+
+    if poisoned { Poison::poisoned(data) } else { Poison::new(data) }
+}
+
+/// Run two dependent guarded stages over two Poisons.
+///
+/// `f` runs under a guard on `a`, and its result is passed to `g`, which runs
+/// under a guard on `b`. The guard on `a` is released before the guard on `b`
+/// is acquired, so a panic in `f` poisons only `a`, leaving `b` untouched
+/// since `g` never runs, and a panic in `g` poisons only `b`.
+///
+/// Both stages run even if a Poison is already poisoned, in which case
+/// PoisonError is returned.
+#[cfg_attr(feature = "tracing", track_caller)]
+pub fn chain<A, B, V, R, F, G>(a: &mut Poison<A>, f: F, b: &mut Poison<B>, g: G) -> LockResult<R>
+                               where A: ?Sized, B: ?Sized,
+                                     F: FnOnce(&mut A) -> V,
+                                     G: FnOnce(&mut B, V) -> R {
+    let (value, a_poisoned) = match a.lock() {
+        Ok(mut guard) => (f(guard.get_mut()), false),
+        Err(err) => (f(err.into_inner().get_mut()), true)
+    };
+
+    let result = map_result(b.lock(), move |mut guard| g(guard.get_mut(), value));
+
+    if a_poisoned {
+        Err(PoisonError::new(result.unwrap_or_else(PoisonError::into_inner)))
+    } else {
+        result
+    }
+}
+
+/// Reseed the random sampling used by lock_sampled and lock_self_healing on
+/// the current thread.
+///
+/// Only available with the `test-util` feature, so that tests can make
+/// sampled invariant checks and healing deterministic. The same seed always
+/// produces the same sequence of sampling decisions.
+#[cfg(any(test, feature = "test-util"))]
+pub fn seed_sampling(seed: u64) {
+    sample::seed(seed)
+}
+
+/// Extension methods for PoisonError, from both std locks and this crate.
+pub trait PoisonErrorExt<T> {
+    /// Get the contained value, ignoring that it is poisoned.
+    fn ignore_poison(self) -> T;
+
+    /// Get the contained value, ignoring that it is poisoned.
+    ///
+    /// An alias for ignore_poison, for symmetry with PoisonError::into_inner.
+    fn into_inner_anyway(self) -> T;
+
+    /// Map the contained value, keeping the error poisoned.
+    fn map<U, F: FnOnce(T) -> U>(self, f: F) -> PoisonError<U>;
+}
+
+impl<T> PoisonErrorExt<T> for PoisonError<T> {
+    #[inline]
+    fn ignore_poison(self) -> T { self.into_inner() }
+
+    #[inline]
+    fn into_inner_anyway(self) -> T { self.into_inner() }
+
+    #[inline]
+    fn map<U, F: FnOnce(T) -> U>(self, f: F) -> PoisonError<U> {
+        PoisonError::new(f(self.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::fmt::Debug;
+    use std::sync::{Mutex, Arc};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use {chain, collect_poison, lock_all, map_lock, map_result_both, try_map_result, DeepHeal, GuardBusy, Invariant, InvariantError, OutOfBounds, Poison, PoisonCommand, PoisonErrorExt, PoisonMode, Poisoned, RawPoison, TryWithError};
+
+    #[test]
+    fn test_poison() {
+        let x1 = Arc::new(Mutex::new(Poison::new(12)));
+        let x2 = x1.clone();
+
+        thread::spawn(move || {
+            let mut _ml = x1.lock().unwrap();
+            let _pl = _ml.lock().unwrap();
+            panic!();
+        }).join().unwrap_err();
+
+        match x2.lock() {
+            Err(mut p) => {
+                p.get_mut().lock().unwrap_err();
+                p.get_mut().heal();
+                p.get_mut().lock().unwrap();
+            },
+            Ok(_) => panic!("Mutex not poisoned?")
+        };
+    }
+
+    #[test]
+    fn test_raw_poison() {
+        let x1 = Arc::new(Mutex::new(RawPoison::new()));
+        let x2 = x1.clone();
+
+        thread::spawn(move || {
+            let mut _ml = x1.lock().unwrap();
+            let _pl = _ml.lock().unwrap();
+            panic!();
+        }).join().unwrap_err();
+
+        match x2.lock() {
+            Err(mut p) => {
+                p.get_mut().lock().unwrap_err();
+                p.get_mut().heal();
+                p.get_mut().lock().unwrap();
+            },
+            Ok(_) => panic!("Mutex not poisoned?")
+        };
+    }
+
+    #[test]
+    fn test_guard_with_rollback() {
+        let mut poison = Poison::new(vec![1, 2, 3]);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = poison.guard_with_rollback(|data| {
+                let saved = data.clone();
+                move |data: &mut Vec<i32>| *data = saved
+            }, |data| {
+                data.push(4);
+                panic!();
+            });
+        })).unwrap_err();
+
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2, 3]);
+
+        poison.heal();
+        let len = poison.guard_with_rollback(|_| |_: &mut Vec<i32>| panic!(), |data| {
+            data.push(4);
+            data.len()
+        }).unwrap();
+        assert_eq!(len, 4);
+    }
+
+    #[test]
+    fn test_guard_reborrow() {
+        let mut poison = Poison::new(1);
+
+        {
+            let mut guard = poison.lock().unwrap();
+            *guard.reborrow().get_mut() += 1;
+            assert_eq!(*guard.get(), 2);
+
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut reborrow = guard.reborrow();
+                *reborrow.get_mut() += 1;
+                panic!();
+            })).unwrap_err();
+        }
+
+        assert_eq!(*poison.get().unwrap_err().into_inner(), 3);
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut poison = Poison::poisoned(vec![1, 2, 3, 4]);
+        let tail = poison.split_off(1);
+
+        assert_eq!(*tail.get().unwrap(), [2, 3, 4]);
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1]);
+
+        let mut poison = Poison::new(String::from("hello world"));
+        assert_eq!(poison.split_off(5).into_inner().unwrap(), " world");
+        assert_eq!(poison.into_inner().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut vec = Vec::with_capacity(64);
+        vec.push(1);
+
+        let mut poison = Poison::poisoned(vec);
+        poison.shrink_to_fit();
+
+        assert!(poison.is_poisoned());
+        assert!(poison.get().unwrap_err().into_inner().capacity() < 64);
+    }
+
+    #[test]
+    fn test_attach() {
+        struct Flag<'a>(&'a mut bool, bool);
+
+        impl<'a> Drop for Flag<'a> {
+            fn drop(&mut self) { *self.0 = self.1; }
+        }
+
+        let mut raw = RawPoison::new();
+        let mut dropped = false;
+
+        {
+            let mut attached = raw.attach(Flag(&mut dropped, false));
+            attached.get_mut().1 = true;
+        }
+
+        assert!(dropped);
+        raw.lock().unwrap();
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _attached = raw.attach(());
+            panic!();
+        })).unwrap_err();
+
+        raw.lock().unwrap_err();
+    }
+
+    #[test]
+    fn test_validate() {
+        struct Sorted(Vec<i32>);
+
+        impl Invariant for Sorted {
+            fn check(&self) -> Result<(), InvariantError> {
+                if self.0.windows(2).all(|w| w[0] <= w[1]) {
+                    Ok(())
+                } else {
+                    Err(InvariantError::new("not sorted"))
+                }
+            }
+        }
+
+        let mut poison = Poison::new(Sorted(vec![1, 2, 3]));
+        poison.validate().unwrap();
+        poison.lock().unwrap().get_mut().0.push(0);
+
+        assert_eq!(poison.validate().unwrap_err().message(), "not sorted");
+        assert!(poison.get().is_err());
+    }
+
+    #[test]
+    fn test_map_lock() {
+        let mut poison = Poison::new((1, 2));
+        *map_lock(poison.lock(), |pair| &mut pair.1).unwrap().get_mut() = 3;
+        assert_eq!(*poison.get().unwrap(), (1, 3));
+
+        let mut poison = Poison::poisoned((1, 2));
+        let mut guard = map_lock(poison.lock(), |pair| &mut pair.0).unwrap_err().into_inner();
+        *guard.get_mut() = 4;
+        drop(guard);
+        assert_eq!(*poison.get().unwrap_err().into_inner(), (4, 2));
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let poison = Poison::new(vec![1]);
+        assert_eq!(*poison.try_clone().unwrap().get().unwrap(), [1]);
+
+        let poison = Poison::poisoned(vec![1]);
+        let clone = poison.try_clone().unwrap_err().into_inner();
+        assert_eq!(*clone.get().unwrap_err().into_inner(), [1]);
+    }
+
+    #[test]
+    fn test_lock_ordered() {
+        use std::sync::Mutex;
+        use {lock_ordered2, lock_ordered3};
+
+        let (a, b, c) = (Mutex::new(Poison::new(1)), Mutex::new(Poison::new(2)),
+                         Mutex::new(Poison::poisoned(3)));
+
+        {
+            let (x, y) = lock_ordered2(&b, &a);
+            assert_eq!((*x.unwrap().get(), *y.unwrap().get()), (2, 1));
+        }
+
+        let (x, y, z) = lock_ordered3(&c, &a, &b);
+        assert_eq!(*z.unwrap().get(), 2);
+        assert_eq!(*y.unwrap().get(), 1);
+        assert_eq!(*x.unwrap_err().into_inner().get(), 3);
+
+        let twice = panic::catch_unwind(|| { let _ = lock_ordered2(&a, &a); });
+        assert!(twice.is_err());
+    }
+
+    #[test]
+    fn test_lock_arc() {
+        let shared = Poison::arc_mutex(vec![1]);
+        Poison::lock_arc(&shared).unwrap().get_mut().push(2);
+
+        let other = shared.clone();
+        thread::spawn(move || {
+            let mut guard = Poison::lock_arc(&other).unwrap();
+            guard.get_mut().clear();
+            panic!();
+        }).join().unwrap_err();
+
+        let guard = Poison::lock_arc(&shared).unwrap_err().into_inner();
+        assert!(guard.get().is_empty());
+    }
+
+    #[test]
+    fn test_lock_owned() {
+        struct Builder { guard: ::OwnedPoisonGuard<Vec<i32>> }
+
+        let shared = Poison::arc_mutex(vec![1]);
+        let mut builder = Builder { guard: Poison::lock_owned(shared.clone()).unwrap() };
+        builder.guard.get_mut().push(2);
+        drop(builder);
+
+        let other = shared.clone();
+        thread::spawn(move || {
+            let mut guard = Poison::lock_owned(other).unwrap();
+            guard.get_mut().push(3);
+            panic!();
+        }).join().unwrap_err();
+
+        let guard = Poison::lock_owned(shared).unwrap_err().into_inner();
+        assert_eq!(*guard.get(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_into_mutex() {
+        let mutex = Poison::new(vec![1]).into_mutex();
+        mutex.lock().unwrap().lock().unwrap().get_mut().push(2);
+
+        let poison = Poison::from_mutex(mutex).unwrap();
+        assert_eq!(poison.into_inner().unwrap(), [1, 2]);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn test_poison_backtrace() {
+        let mut poison = Poison::new(());
+        assert!(poison.poison_backtrace().is_none());
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+
+        assert!(poison.poison_backtrace().is_some());
+        poison.heal();
+        assert!(poison.poison_backtrace().is_none());
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn test_poison_backtrace_after_set_hook() {
+        use std::cell::Cell;
+
+        thread_local!(static CALLS: Cell<usize> = const { Cell::new(0) });
+
+        // Install the backtrace hook, then replace it with one of our own
+        // while no guard is live on this thread.
+        let mut poison = Poison::new(());
+        drop(poison.lock().unwrap());
+
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            previous(info)
+        }));
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+
+        assert!(poison.poison_backtrace().is_some());
+        assert_eq!(CALLS.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn test_map_elems() {
+        let poison = Poison::new(vec![1, 2, 3]).map_elems(|x| x.to_string());
+        assert_eq!(poison.into_inner().unwrap(), ["1", "2", "3"]);
+
+        let poison = Poison::poisoned(vec![1]).map_elems(|x| x * 2);
+        assert_eq!(poison.into_inner().unwrap_err().into_inner(), [2]);
+    }
+
+    #[test]
+    fn test_get_mut_or_init() {
+        let mut poison = Poison::new(None);
+        *poison.get_mut_or_init(|| 1).unwrap() += 1;
+        assert_eq!(*poison.get_mut_or_init(|| panic!()).unwrap(), 2);
+
+        let mut poison = Poison::poisoned(None);
+        assert_eq!(*poison.get_mut_or_init(|| 1).unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_ord() {
+        let mut poisons = vec![
+            Poison::poisoned(1),
+            Poison::new(3),
+            Poison::poisoned(0),
+            Poison::new(2)
+        ];
+        poisons.sort();
+
+        assert_eq!(poisons, [
+            Poison::new(2),
+            Poison::new(3),
+            Poison::poisoned(0),
+            Poison::poisoned(1)
+        ]);
+        assert!(Poison::new(1) != Poison::poisoned(1));
+    }
+
+    #[test]
+    fn test_recover_with() {
+        let mut poison = Poison::new(vec![1]);
+        assert_eq!(*poison.recover_with(|| panic!()).get(), [1]);
+
+        let mut poison = Poison::poisoned(vec![1]);
+        poison.recover_with(Vec::new).get_mut().push(2);
+        assert_eq!(*poison.get().unwrap(), [2]);
+    }
+
+    #[test]
+    fn test_lock_self_healing() {
+        let mut poison = Poison::poisoned(1);
+
+        assert!(poison.lock_self_healing(0.0).is_err());
+        assert!(poison.is_poisoned());
+
+        assert!(poison.lock_self_healing(1.0).is_ok());
+        assert!(!poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_lock_or_recover() {
+        let mut poison = Poison::new(vec![1]);
+        assert_eq!(*poison.lock_or_recover(|_| panic!()).unwrap().get(), [1]);
+
+        let mut poison = Poison::poisoned(vec![1]);
+        assert!(poison.lock_or_recover(|_| false).is_err());
+        assert!(poison.is_poisoned());
+
+        poison.lock_or_recover(|data| { data.clear(); true }).unwrap().get_mut().push(2);
+        assert_eq!(*poison.get().unwrap(), [2]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_with_async() {
+        use std::future::{self, Future};
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Waker};
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut poison = Poison::new(vec![1]);
+
+        {
+            let mut op = poison.with_async(|data| { data.push(2); future::ready(data.len()) });
+            let poll = Pin::new(&mut op).poll(&mut cx);
+            assert!(matches!(poll, Poll::Ready(Ok(2))));
+        }
+        assert!(!poison.is_poisoned());
+
+        {
+            let mut op = poison.with_async(|_| future::pending::<()>());
+            assert!(Pin::new(&mut op).poll(&mut cx).is_pending());
+        }
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_defer() {
+        let mut poison = Poison::new(vec![1]);
+
+        {
+            let mut guard = poison.lock().unwrap();
+            guard.defer(|data| data.push(3));
+            guard.get_mut().push(2);
+        }
+        assert_eq!(*poison.get().unwrap(), [1, 2, 3]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.lock().unwrap();
+            guard.defer(|data| data.clear());
+            panic!()
+        }));
+        assert!(poison.get().unwrap_err().into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_lock_sampled() {
+        let mut poison = Poison::new(0);
+
+        *poison.lock_sampled(0.0, |x| *x < 10).unwrap().get_mut() = 20;
+        assert!(!poison.is_poisoned());
+
+        *poison.lock_sampled(1.0, |x| *x < 30).unwrap().get_mut() = 25;
+        assert!(!poison.is_poisoned());
+
+        ::seed_sampling(1);
+        let checks = (0..1000).filter(|_| ::sample::sample(0.5)).count();
+        assert!(checks > 400 && checks < 600);
+
+        *poison.lock_sampled(1.0, |x| *x < 10).unwrap().get_mut() = 30;
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_lock_sampled_seeded() {
+        use std::cell::Cell;
+
+        // Run a failing check on a sample of 100 drops, returning which poisoned.
+        fn run(rate: f64, checks: &Cell<usize>) -> Vec<bool> {
+            let mut poison = Poison::new(0);
+            (0..100).map(|_| {
+                drop(poison.lock_sampled(rate, |_| { checks.set(checks.get() + 1); false }).unwrap());
+                let poisoned = poison.is_poisoned();
+                poison.heal();
+                poisoned
+            }).collect()
+        }
+
+        let checks = Cell::new(0);
+
+        ::seed_sampling(7);
+        let first = run(0.5, &checks);
+        let count = checks.get();
+        assert!(count > 0 && count < 100);
+        assert_eq!(first.iter().filter(|&&poisoned| poisoned).count(), count);
+
+        ::seed_sampling(7);
+        assert_eq!(run(0.5, &checks), first);
+        assert_eq!(checks.get(), 2 * count);
+
+        checks.set(0);
+        assert!(run(0.0, &checks).iter().all(|&poisoned| !poisoned));
+        assert_eq!(checks.get(), 0);
+    }
+
+    #[test]
+    fn test_to_cow() {
+        use std::borrow::Cow;
+
+        let poison = Poison::new(vec![1]);
+        assert!(matches!(poison.to_cow(), Cow::Borrowed(data) if *data == [1]));
+
+        let poison = Poison::poisoned(vec![1]);
+        assert!(matches!(poison.to_cow(), Cow::Owned(data) if data == [1]));
+    }
+
+    #[test]
+    fn test_lock_rollback() {
+        let mut poison = Poison::new(vec![1]);
+
+        poison.lock_rollback().unwrap().get_mut().push(2);
+        assert_eq!(*poison.get().unwrap(), [1, 2]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.lock_rollback().unwrap();
+            guard.get_mut().clear();
+            panic!()
+        }));
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_consume() {
+        let mut poison = Poison::new(vec![1]);
+
+        let len = poison.consume(|mut data| { data.push(2); let len = data.len(); (data, len) });
+        assert_eq!(len.unwrap(), 2);
+        assert_eq!(*poison.get().unwrap(), [1, 2]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| poison.consume(|_| -> (_, ()) { panic!() })));
+        assert!(poison.get().unwrap_err().into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_with_budget() {
+        let mut poison = Poison::new(0);
+
+        let remaining = poison.with_budget(10, |data, budget| {
+            for _ in 0..4 { budget.charge(2); *data += 1; }
+            budget.remaining()
+        });
+        assert_eq!(remaining.unwrap(), 2);
+        assert!(!poison.is_poisoned());
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            poison.with_budget(10, |data, budget| loop { budget.charge(1); *data += 1; })
+        }));
+        assert_eq!(*poison.get().unwrap_err().into_inner(), 14);
+    }
+
+    #[test]
+    fn test_apply() {
+        use PoisonOp;
+
+        let push = PoisonOp::new(|data: &mut Vec<i32>, x| { data.push(x); data.len() });
+        let (mut a, mut b) = (Poison::new(vec![1]), Poison::poisoned(vec![]));
+
+        assert_eq!(a.apply(&push, 2).unwrap(), 2);
+        assert_eq!(b.apply(&push, 3).unwrap_err().into_inner(), 1);
+
+        let fail = PoisonOp::new(|_: &mut Vec<i32>, ()| panic!());
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| a.apply(&fail, ())));
+        assert_eq!(*a.get().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_run_command() {
+        let mut poison = Poison::new(vec![1]);
+        let mut queue: Vec<PoisonCommand<Vec<i32>>> = vec![Box::new(|data| data.push(2)),
+                                                          Box::new(|_| panic!()),
+                                                          Box::new(|data| data.push(3))];
+
+        poison.run_command(queue.remove(0)).unwrap();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| poison.run_command(queue.remove(0))));
+        assert!(poison.run_command(queue.remove(0)).is_err());
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_observe_only() {
+        let mut poison = Poison::new_observing(1);
+        assert!(poison.is_observe_only());
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!()
+        }));
+        assert!(!poison.is_poisoned());
+        assert_eq!(poison.observed_poisonings(), 1);
+        assert_eq!(poison.debug_state().observed_poisonings, 1);
+
+        poison.freeze();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!()
+        }));
+        assert_eq!(poison.observed_poisonings(), 1);
+        poison.thaw();
+
+        poison.set_observe_only(false);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!()
+        }));
+        assert!(poison.is_poisoned());
+    }
+
+    #[cfg(all(debug_assertions, feature = "reentrancy-check"))]
+    #[test]
+    fn test_reentrancy_detected() {
+        let mut poison = Poison::new(1);
+
+        {
+            let mut guard = poison.lock().unwrap();
+            let _nested = guard.reborrow();
+        }
+
+        let ptr: *mut Poison<i32> = &mut poison;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _outer = unsafe { &mut *ptr }.lock();
+            let _inner = unsafe { &mut *ptr }.lock();
+        }));
+
+        let message = result.unwrap_err();
+        assert!(message.downcast_ref::<&str>().unwrap().starts_with("reentrant lock detected"));
+        assert_eq!(RawPoison::current_guard_depth(), 0);
+    }
+
+    #[test]
+    fn test_reentrancy_after_forget() {
+        use std::mem;
+
+        let mut leaked = Poison::new(1);
+        mem::forget(leaked.lock());
+
+        let relock = panic::catch_unwind(AssertUnwindSafe(|| { let _ = leaked.lock(); }));
+        assert_eq!(relock.is_err(), cfg!(all(debug_assertions, feature = "reentrancy-check")));
+
+        // A Poison reusing the storage of one with a leaked guard is unaffected.
+        for i in 0..2 {
+            let mut poison = Poison::new(i);
+            if i == 0 { mem::forget(poison.lock()); } else { let _guard = poison.lock().unwrap(); }
+        }
+    }
+
+    #[test]
+    fn test_lock_timed() {
+        let durations = Arc::new(Mutex::new(Vec::new()));
+        let recorded = durations.clone();
+
+        let mut poison = Poison::new(());
+        poison.set_on_guard_duration(move |held| recorded.lock().unwrap().push(held));
+
+        {
+            let _guard = poison.lock_timed().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(poison.lock().unwrap());
+
+        let durations = durations.lock().unwrap();
+        assert_eq!(durations.len(), 1);
+        assert!(durations[0] >= Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_lock_checked() {
+        let mut poison = Poison::new(vec![1, 2]);
+
+        poison.lock_checked(|data| data.len() % 2 == 0).unwrap().get_mut().extend([3, 4]);
+        assert!(!poison.is_poisoned());
+
+        poison.lock_checked(|data| data.len() % 2 == 0).unwrap().get_mut().push(5);
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_lock_cancellable() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let token = Arc::new(AtomicBool::new(false));
+        let mut poison = Poison::new(1);
+
+        *poison.lock_cancellable(token.clone()).unwrap().get_mut() += 1;
+        assert!(!poison.is_poisoned());
+
+        {
+            let guard = poison.lock_cancellable(token.clone()).unwrap();
+            token.store(true, Ordering::Release);
+            assert!(guard.is_cancelled());
+        }
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_lock_or_else() {
+        let mut poison = Poison::new(1);
+        assert_eq!(*poison.lock_or_else(|_| "poisoned").unwrap().get(), 1);
+
+        let mut poison = Poison::poisoned(1);
+        let err = poison.lock_or_else(|guard| format!("poisoned at {}", guard.get()));
+        assert_eq!(err.unwrap_err(), "poisoned at 1");
+    }
+
+    // A subscriber recording the location of each guard span and the message
+    // of each event.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Recorder {
+        spans: std::sync::atomic::AtomicUsize,
+        locations: Mutex<Vec<String>>,
+        events: Mutex<Vec<String>>
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldVisitor<'a>(&'a str, Option<String>);
+
+    #[cfg(feature = "tracing")]
+    impl<'a> tracing::field::Visit for FieldVisitor<'a> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+            if field.name() == self.0 { self.1 = Some(format!("{:?}", value)); }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool { true }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut visitor = FieldVisitor("location", None);
+            span.record(&mut visitor);
+            self.locations.lock().unwrap().extend(visitor.1);
+            tracing::span::Id::from_u64(self.spans.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut visitor = FieldVisitor("message", None);
+            event.record(&mut visitor);
+            self.events.lock().unwrap().extend(visitor.1);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_guard_location() {
+        use std::panic::Location;
+
+        let recorder = Arc::new(Recorder::default());
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut expected = Vec::new();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut poison = Poison::new(vec![1, 2]);
+            let (guard, here) = (poison.lock_deadline(deadline), Location::caller());
+            expected.push(here);
+            drop(guard);
+
+            let (guard, here) = (poison.lock_index(1), Location::caller());
+            expected.push(here);
+            drop(guard);
+
+            let mut raw = RawPoison::new();
+            let (guard, here) = (raw.lock_deadline(deadline), Location::caller());
+            expected.push(here);
+            drop(guard);
+        });
+
+        // The columns differ, since the span records the start of the call.
+        let locations = recorder.locations.lock().unwrap();
+        let lines: Vec<&str> = locations.iter().map(|at| at.rsplit_once(':').unwrap().0).collect();
+        let expected: Vec<String> = expected.iter().map(|at| format!("{}:{}", at.file(), at.line())).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_tracing_events() {
+        let recorder = Arc::new(Recorder::default());
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut poison = Poison::new(());
+            let _ = poison.scope_capturing(|_| panic!());
+            poison.heal();
+            poison.heal();
+
+            poison.set_observe_only(true);
+            let _ = poison.scope_capturing(|_| panic!());
+        });
+
+        assert_eq!(*recorder.events.lock().unwrap(), ["poisoned", "healed", "would have poisoned"]);
+    }
+
+    // A recorder counting the increments of each counter poison emits.
+    #[cfg(feature = "metrics")]
+    #[derive(Default)]
+    struct Counts {
+        poisoned: Arc<std::sync::atomic::AtomicU64>,
+        healed: Arc<std::sync::atomic::AtomicU64>,
+        observed: Arc<std::sync::atomic::AtomicU64>
+    }
+
+    #[cfg(feature = "metrics")]
+    impl Counts {
+        fn get(&self) -> [u64; 3] {
+            use std::sync::atomic::Ordering::SeqCst;
+            [self.poisoned.load(SeqCst), self.healed.load(SeqCst), self.observed.load(SeqCst)]
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::Recorder for Counts {
+        fn describe_counter(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+
+        fn describe_gauge(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+
+        fn describe_histogram(&self, _: metrics::KeyName, _: Option<metrics::Unit>, _: metrics::SharedString) {}
+
+        fn register_counter(&self, key: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Counter {
+            match key.name() {
+                "poison.poisoned" => metrics::Counter::from_arc(self.poisoned.clone()),
+                "poison.healed" => metrics::Counter::from_arc(self.healed.clone()),
+                "poison.observed" => metrics::Counter::from_arc(self.observed.clone()),
+                _ => metrics::Counter::noop()
+            }
+        }
+
+        fn register_gauge(&self, _: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Gauge {
+            metrics::Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &metrics::Key, _: &metrics::Metadata<'_>) -> metrics::Histogram {
+            metrics::Histogram::noop()
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_metrics() {
+        let counts = Counts::default();
+
+        metrics::with_local_recorder(&counts, || {
+            let mut poison = Poison::new(());
+            poison.heal();
+            assert_eq!(counts.get(), [0, 0, 0]);
+
+            let _ = poison.scope_capturing(|_| panic!());
+            assert_eq!(counts.get(), [1, 0, 0]);
+
+            let _ = poison.scope_capturing(|_| panic!());
+            assert_eq!(counts.get(), [1, 0, 0]);
+
+            poison.heal();
+            poison.heal();
+            assert_eq!(counts.get(), [1, 1, 0]);
+
+            poison.set_observe_only(true);
+            let _ = poison.scope_capturing(|_| panic!());
+            assert_eq!(counts.get(), [1, 1, 1]);
+        });
+    }
+
+    #[test]
+    fn test_try_guard() {
+        use std::mem;
+
+        let mut first = Poison::new(1);
+        let mut second = Poison::new(2);
+
+        {
+            let _guard = first.try_guard().unwrap().unwrap();
+            assert_eq!(*second.try_guard().unwrap().unwrap().get(), 2);
+        }
+
+        mem::forget(first.lock());
+        assert_eq!(first.try_guard().unwrap_err(), GuardBusy);
+        assert_eq!(*second.try_guard().unwrap().unwrap().get(), 2);
+        assert!(Poison::poisoned(3).try_guard().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_lock_as() {
+        struct Wrapper { inner: Vec<i32> }
+
+        let mut poison = Poison::new(Wrapper { inner: vec![1] });
+        {
+            let mut guard = poison.lock_as(|w: &mut Wrapper| &mut w.inner).unwrap();
+            guard.get_mut().push(2);
+            guard.get_mut().push(3);
+        }
+        assert_eq!(poison.get().unwrap().inner, [1, 2, 3]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.lock_as(|w: &mut Wrapper| &mut w.inner).unwrap();
+            guard.get_mut().clear();
+            panic!();
+        }));
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_data_mut() {
+        let mut poison = Poison::new(1);
+        if let Some(data) = poison.data_mut() { *data += 1; }
+        assert_eq!(*poison.get().unwrap(), 2);
+
+        let mut poison = Poison::poisoned(1);
+        assert!(poison.data_mut().is_none());
+    }
+
+    #[test]
+    fn test_get_unchecked() {
+        let poison = Poison::new(vec![1]);
+        assert!(!poison.is_poisoned());
+        assert_eq!(*unsafe { poison.get_unchecked() }, [1]);
+    }
+
+    #[test]
+    fn test_map_ref() {
+        let poison = Poison::new((1, String::from("a")));
+        assert_eq!(poison.map_ref(|pair| &*pair.1).unwrap(), "a");
+
+        let poison = Poison::poisoned((1, 2));
+        assert_eq!(*poison.map_ref(|pair| &pair.0).unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_poison_error_ext() {
+        let poison = Poison::poisoned(vec![1, 2]);
+        let err = poison.get().unwrap_err().map(|data| data.len());
+        assert_eq!(err.ignore_poison(), 2);
+
+        let mutex = Mutex::new(1);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!();
+        }));
+        assert_eq!(*mutex.lock().unwrap_err().into_inner_anyway(), 1);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut poison = Poison::new(vec![1]);
+        poison.extend(vec![2, 3]);
+        assert_eq!(*poison.get().unwrap(), [1, 2, 3]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            poison.extend((4..8).map(|i| if i < 6 { i } else { panic!() }));
+        }));
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_flatten_result() {
+        use std::sync::PoisonError;
+        use flatten_result;
+
+        assert_eq!(flatten_result(Ok(Ok(1))).unwrap(), 1);
+        assert_eq!(flatten_result(Ok(Err(PoisonError::new(2)))).unwrap_err().into_inner(), 2);
+        assert_eq!(flatten_result(Err(PoisonError::new(Ok(3)))).unwrap_err().into_inner(), 3);
+        assert_eq!(flatten_result(Err(PoisonError::new(Err(PoisonError::new(4)))))
+                       .unwrap_err().into_inner(), 4);
+    }
+
+    #[test]
+    fn test_map_result_both() {
+        let ok = map_result_both(Poison::new(1).into_inner(), |x| x + 1, |x| x - 1);
+        assert_eq!(ok.unwrap(), 2);
+
+        let err = map_result_both(Poison::poisoned(1).into_inner(), |x| x + 1, |x| x - 1);
+        assert_eq!(err.unwrap_err().into_inner(), 0);
+    }
+
+    #[test]
+    fn test_collect_poison() {
+        let healthy = collect_poison(vec![Poison::new(1), Poison::new(2)]);
+        assert_eq!(*healthy.get().unwrap(), [1, 2]);
+
+        let poisoned = collect_poison(vec![Poison::new(1), Poison::poisoned(2)]);
+        assert_eq!(*poisoned.get().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_lock_index() {
+        let mut poison = Poison::new(vec![1, 2, 3]);
+
+        *poison.lock_index(1).unwrap().unwrap().get_mut() = 5;
+        assert_eq!(poison.lock_index(3).unwrap_err(), OutOfBounds { index: 3, len: 3 });
+        assert_eq!(*poison.get().unwrap(), [1, 5, 3]);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock_index(0).unwrap().unwrap();
+            panic!()
+        }));
+        assert!(poison.lock_index(2).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_lock_all() {
+        let mut poisons = vec![Poison::new(1), Poison::new(2)];
+        for mut guard in lock_all(&mut poisons).unwrap() {
+            *guard.get_mut() += 1;
+        }
+        assert_eq!(*poisons[1].get().unwrap(), 3);
+
+        poisons.push(Poison::poisoned(4));
+        let guards = lock_all(&mut poisons).unwrap_err().into_inner();
+        assert_eq!(guards.len(), 3);
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut a = Poison::new(vec![1, 2]);
+        let mut b = Poison::new(0);
+
+        let total = chain(&mut a, |a| a.iter().sum::<i32>(), &mut b, |b, sum| { *b += sum; *b });
+        assert_eq!(total.unwrap(), 3);
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            chain(&mut a, |a| a.push(3), &mut b, |_, ()| panic!())
+        }));
+        assert!(!a.is_poisoned());
+        assert!(b.is_poisoned());
+
+        b.heal();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            chain(&mut a, |_| panic!(), &mut b, |_, ()| ())
+        }));
+        assert!(a.is_poisoned());
+        assert!(!b.is_poisoned());
+
+        assert!(chain(&mut a, |a| a.len(), &mut b, |_, len| len).is_err());
+    }
+
+    #[test]
+    fn test_with_retry() {
+        let mut poison = Poison::new(0);
+        let result = poison.with_retry(3, |tries| {
+            *tries += 1;
+            if *tries < 3 { panic!() }
+            *tries
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert!(!poison.is_poisoned());
+
+        assert!(poison.with_retry(2, |_| -> () { panic!() }).is_err());
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_scope_capturing() {
+        let mut poison = Poison::new(1);
+        assert_eq!(poison.scope_capturing(|data| *data += 1), Ok(()));
+
+        assert_eq!(poison.scope_capturing(|_| panic!("bad {}", 1)), Err(String::from("bad 1")));
+        assert!(poison.is_poisoned());
+
+        let result = poison.scope_capturing(|_| panic::panic_any(1));
+        assert_eq!(result.unwrap_err(), "non-string panic");
+    }
+
+    #[test]
+    #[cfg(feature = "reason")]
+    fn test_poison_reason() {
+        let mut poison = Poison::new(1);
+        let _ = poison.scope_capturing(|_| ());
+        assert_eq!(poison.poison_reason(), None);
+
+        let _ = poison.scope_capturing(|_| panic!("bad {}", 1));
+        assert_eq!(poison.poison_reason(), Some("bad 1"));
+
+        poison.heal();
+        assert_eq!(poison.poison_reason(), None);
+
+        let _ = poison.scope_capturing(|_| panic!("original"));
+        assert_eq!(poison.scope_capturing(|_| panic!("later")), Err(String::from("later")));
+        assert_eq!(poison.poison_reason(), Some("original"));
+        assert_eq!(poison.poison_reasons().last().map(String::as_str), Some("original"));
+
+        let mut poison = Poison::poisoned(1);
+        let _ = poison.scope_capturing(|_| panic!("unrelated"));
+        assert!(poison.is_poisoned());
+        assert_eq!(poison.poison_reason(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "reason")]
+    fn test_poison_reasons() {
+        let mut poison = Poison::new(1);
+
+        let _ = poison.scope_capturing(|_| panic!("first"));
+        poison.heal();
+        let _ = poison.scope_capturing(|_| panic!("second"));
+        assert_eq!(poison.poison_reasons(), ["first", "second"]);
+
+        for _ in 0..20 {
+            poison.heal();
+            let _ = poison.scope_capturing(|_| panic!("again"));
+        }
+        assert_eq!(poison.poison_reasons().len(), ::REASON_CAPACITY);
+
+        poison.reset();
+        assert!(!poison.is_poisoned());
+        assert!(poison.poison_reasons().is_empty());
+    }
+
+    #[test]
+    fn test_get_pair() {
+        assert_eq!(Poison::new(1).get_pair(), (&1, false));
+        assert_eq!(Poison::poisoned(1).get_pair(), (&1, true));
+    }
+
+    #[test]
+    fn test_deep_heal() {
+        struct Node {
+            children: Vec<Poison<Node>>,
+            raw: RawPoison
+        }
+
+        impl DeepHeal for Node {
+            fn deep_heal(&mut self) {
+                self.children.deep_heal();
+                self.raw.deep_heal();
+            }
+        }
+
+        let leaf = Poison::poisoned(Node { children: vec![], raw: RawPoison::poisoned() });
+        let mut root = Poison::poisoned(Node { children: vec![leaf], raw: RawPoison::new() });
+        root.deep_heal();
+
+        let root = root.into_inner().unwrap();
+        let mut leaf = root.children.into_iter().next().unwrap().into_inner().unwrap();
+        leaf.raw.lock().unwrap();
+    }
+
+    #[cfg(feature = "timestamp")]
+    #[test]
+    fn test_poisoned_since() {
+        let mut poison = Poison::new(());
+        assert!(poison.poisoned_since().is_none());
+
+        let before = Instant::now();
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+
+        assert!(poison.poisoned_since().unwrap() >= before);
+        poison.heal();
+        assert!(poison.poisoned_since().is_none());
+    }
+
+    #[cfg(feature = "timestamp")]
+    #[test]
+    fn test_heal_if_older_than() {
+        let mut poison = Poison::new(());
+        assert!(!poison.heal_if_older_than(Duration::from_secs(0)));
+
+        let mut poison = Poison::poisoned(());
+        assert!(!poison.heal_if_older_than(Duration::from_secs(3600)));
+        assert!(poison.heal_if_older_than(Duration::from_secs(0)));
+        poison.get().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "timestamp")]
+    fn test_heal_if_older_than_frozen() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let heals = Arc::new(AtomicUsize::new(0));
+        let counter = heals.clone();
+
+        let mut poison = Poison::poisoned(());
+        poison.freeze();
+        poison.set_on_heal(move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        assert!(poison.heal_if_older_than(Duration::from_secs(0)));
+        assert!(!poison.is_poisoned());
+        assert!(poison.is_frozen());
+        assert_eq!(heals.load(Ordering::SeqCst), 1);
+
+        assert!(!poison.heal_if_older_than(Duration::from_secs(0)));
+        assert_eq!(heals.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_as_result() {
+        assert_eq!(Poison::new(1).as_result(), Ok(&1));
+        assert_eq!(Poison::poisoned(1).as_result(), Err(&1));
+    }
+
+    #[test]
+    fn test_unsize() {
+        let mut boxed: Box<Poison<[i32]>> = Box::new(Poison::new([1, 2, 3]));
+        boxed.lock().unwrap().get_mut()[0] = 4;
+        assert_eq!(*boxed.get().unwrap(), [4, 2, 3]);
+
+        let mut concrete = Poison::poisoned(1);
+        let erased: &mut Poison<dyn Debug> = &mut concrete;
+        erased.heal();
+        assert!(!concrete.is_poisoned());
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_coerce_unsized() {
+        let sized: Poison<Box<[i32; 3]>> = Poison::poisoned(Box::new([1, 2, 3]));
+        let unsized_: Poison<Box<[i32]>> = sized;
+        assert_eq!(unsized_.get().unwrap_err().into_inner().len(), 3);
+
+        let mut poison = Poison::new([1, 2, 3]);
+        let guard: ::PoisonGuard<[i32; 3]> = poison.lock().unwrap();
+        let mut guard: ::PoisonGuard<[i32]> = guard;
+        guard.get_mut()[2] = 4;
+        assert_eq!(guard.get().len(), 3);
+        drop(guard);
+        assert_eq!(*poison.get().unwrap(), [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_flatten() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        assert!(!Poison::new(Poison::new(1)).flatten().is_poisoned());
+        assert!(Poison::poisoned(Poison::new(1)).flatten().is_poisoned());
+        assert!(Poison::new(Poison::poisoned(1)).flatten().is_poisoned());
+        assert!(Poison::poisoned(Poison::poisoned(1)).flatten().is_poisoned());
+
+        let heals = Arc::new(AtomicUsize::new(0));
+        let counter = heals.clone();
+
+        let mut inner = Poison::new(1);
+        inner.freeze();
+        inner.set_on_heal(move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        let mut outer = Poison::new(inner);
+        let _ = outer.scope_capturing(|_| panic!("outer"));
+
+        let mut flat = outer.flatten();
+        assert!(flat.is_poisoned());
+        assert!(flat.is_frozen());
+        #[cfg(feature = "reason")]
+        assert_eq!(flat.poison_reason(), Some("outer"));
+
+        flat.heal();
+        assert_eq!(heals.load(Ordering::SeqCst), 1);
+
+        let mut inner = Poison::new(1);
+        inner.set_observe_only(true);
+        let flat = Poison::poisoned(inner).flatten();
+        assert!(!flat.is_poisoned());
+        assert_eq!(flat.observed_poisonings(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "audit")]
+    fn test_flatten_history() {
+        let flat = Poison::poisoned(Poison::new(1)).flatten();
+        assert!(flat.is_poisoned());
+        assert_eq!(flat.debug_state().recent_poisonings, 1);
+        assert!(matches!(flat.history(), [::PoisonTransition::Poisoned(_)]));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut poison = Poison::new(vec![1]);
+        let snap = poison.snapshot();
+        assert!(!snap.is_poisoned());
+
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.lock().unwrap();
+            guard.get_mut().push(2);
+            panic!();
+        }));
+        assert!(poison.is_poisoned());
+
+        poison.restore(snap);
+        assert_eq!(*poison.get().unwrap(), [1]);
+
+        let snap = Poison::poisoned(2).snapshot();
+        let mut poison = Poison::new(1);
+        poison.restore(snap);
+        assert_eq!(*poison.get().unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_into_arc_handle() {
+        let (data, handle) = Poison::new(vec![1]).into_arc_handle();
+        assert!(!handle.is_poisoned());
+
+        let shared = handle.clone();
+        thread::spawn(move || shared.poison()).join().unwrap();
+        assert!(handle.is_poisoned());
+        assert_eq!(*data, [1]);
+
+        let (_, handle) = Poison::poisoned(()).into_arc_handle();
+        assert!(handle.is_poisoned());
+    }
+
+    #[test]
+    fn test_erase() {
+        let erased = vec![Poison::new(1u8).erase(), Poison::poisoned("a").erase()];
+        let mut erased = erased.into_iter();
+
+        let first = erased.next().unwrap().downcast::<u8>().unwrap();
+        assert_eq!(first.into_inner().unwrap(), 1);
+
+        let second = erased.next().unwrap().downcast::<u8>().unwrap_err();
+        let second = second.downcast::<&str>().unwrap();
+        assert_eq!(second.into_inner().unwrap_err().into_inner(), "a");
+    }
+
+    #[test]
+    fn test_try_with() {
+        let mut poison = Poison::new(1);
+        assert_eq!(poison.try_with(|data| { *data += 1; Ok::<_, ()>(*data) }), Ok(2));
+        assert_eq!(poison.try_with(|_| Err::<(), _>("failed")), Err(TryWithError::Inner("failed")));
+        assert!(!poison.is_poisoned());
+
+        let mut poison = Poison::poisoned(1);
+        assert_eq!(poison.try_with(|_| -> Result<(), ()> { panic!() }), Err(TryWithError::Poisoned));
+    }
+
+    #[test]
+    fn test_current_guard_depth() {
+        use std::mem;
+
+        let mut first = Poison::new(());
+        let mut second = RawPoison::new();
+        assert_eq!(RawPoison::current_guard_depth(), 0);
+
+        let mut guard = first.lock().unwrap();
+        let reborrow = guard.reborrow();
+        let raw = second.lock().unwrap();
+        assert_eq!(RawPoison::current_guard_depth(), 3);
+
+        drop(reborrow);
+        drop(raw);
+        assert_eq!(RawPoison::current_guard_depth(), 1);
+
+        thread::spawn(|| assert_eq!(RawPoison::current_guard_depth(), 0)).join().unwrap();
+
+        drop(guard);
+        assert_eq!(RawPoison::current_guard_depth(), 0);
+
+        thread::spawn(|| {
+            let mut leaked = Poison::new(());
+            mem::forget(leaked.lock());
+            assert_eq!(RawPoison::current_guard_depth(), 1);
+
+            let mut other = Poison::new(());
+            drop(other.lock());
+            assert_eq!(RawPoison::current_guard_depth(), 1);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn test_map_or() {
+        assert_eq!(Poison::new(1).map_or(0, |x| x + 1), 2);
+        assert_eq!(Poison::poisoned(1).map_or(0, |x| x + 1), 0);
+        assert_eq!(Poison::new(1).map_or_else(|x| x - 1, |x| x + 1), 2);
+        assert_eq!(Poison::poisoned(1).map_or_else(|x| x - 1, |x| x + 1), 0);
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(Poison::new(1).contains(&1));
+        assert!(!Poison::new(1).contains(&2));
+        assert!(!Poison::poisoned(1).contains(&1));
+        assert!(Poison::new(String::from("a")).contains("a"));
+    }
+
+    #[test]
+    fn test_ok_err() {
+        assert_eq!(Poison::new(1).ok(), Some(1));
+        assert_eq!(Poison::poisoned(1).ok(), None);
+        assert_eq!(Poison::new(1).err(), None);
+        assert_eq!(Poison::poisoned(1).err(), Some(1));
+    }
+
+    #[test]
+    fn test_lock_mode() {
+        let mut raw = RawPoison::new();
+        raw.lock_mode(PoisonMode::OnPanic).unwrap();
+        raw.lock().unwrap().commit();
+        assert!(!raw.is_poisoned());
+
+        raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap().commit();
+        assert!(!raw.is_poisoned());
+
+        raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+        assert!(raw.is_poisoned());
+
+        let mut poison = Poison::new(1);
+        {
+            let mut guard = poison.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+            *guard.reborrow().get_mut() += 1;
+            guard.commit();
+        }
+        assert_eq!(*poison.get().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_force_poison_on_drop() {
+        let mut raw = RawPoison::new();
+        raw.lock().unwrap().force_poison_on_drop();
+        assert!(raw.is_poisoned());
+
+        let mut raw = RawPoison::new();
+        {
+            let mut guard = raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+            guard.commit();
+            guard.force_poison_on_drop();
+        }
+        assert!(raw.is_poisoned());
+
+        let mut poison = Poison::new(1);
+        poison.freeze();
+        poison.lock().unwrap().into_raw().force_poison_on_drop();
+        assert!(!poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut poison = Poison::new(vec![1]);
+        let len = poison.lock().unwrap().finish(|data| { data.push(2); Ok::<_, ()>(data.len()) });
+        assert_eq!(len, Ok(2));
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = poison.lock().unwrap().finish(|_| -> Result<(), ()> { panic!() });
+        })).unwrap_err();
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut raw = RawPoison::new();
+        {
+            let mut guard = raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+            assert!(guard.commit());
+        }
+        assert!(!raw.is_poisoned());
+
+        {
+            let mut guard = raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+            assert!(guard.commit());
+            assert!(!guard.commit());
+        }
+        assert!(!raw.is_poisoned());
+
+        assert!(!raw.lock().unwrap().commit());
+
+        drop(raw.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap());
+        assert!(raw.is_poisoned());
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut poison = Poison::new(HashMap::new());
+        *poison.entry("a").unwrap().or_insert(1).get_mut() += 1;
+        poison.entry("a").unwrap().and_modify(|v| *v += 1).or_default();
+        assert_eq!(poison.get().unwrap()["a"], 3);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            poison.entry("b").unwrap().or_insert_with(|| panic!());
+        })).unwrap_err();
+
+        assert!(poison.entry("a").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut poison = Poison::poisoned(1);
+        poison.set(2);
+        assert_eq!(*poison.get().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_replace_if_poisoned() {
+        let mut poison = Poison::new(1);
+        assert_eq!(poison.replace_if_poisoned(2), None);
+        assert_eq!(*poison.get().unwrap(), 1);
+
+        let mut poison = Poison::poisoned(1);
+        assert_eq!(poison.replace_if_poisoned(2), Some(1));
+        assert_eq!(*poison.get().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_degrade() {
+        let mut poison = Poison::new(1);
+        poison.degrade();
+        assert!(poison.is_degraded());
+        assert!(poison.lock().unwrap().is_degraded());
+
+        poison.heal();
+        assert!(!poison.is_degraded());
+
+        poison.degrade();
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        }));
+        assert!(poison.is_poisoned());
+        assert!(!poison.is_degraded());
+
+        poison.degrade();
+        assert!(!poison.is_degraded());
+    }
+
+    #[test]
+    fn test_try_heal() {
+        let mut poison = Poison::poisoned(vec![1, 2]);
+        assert!(!poison.try_heal(|data| data.is_empty()));
+        assert!(poison.is_poisoned());
+
+        assert!(poison.try_heal(|data| data.len() == 2));
+        assert!(!poison.is_poisoned());
+        assert!(!poison.try_heal(|_| true));
+    }
+
+    #[test]
+    fn test_heal_scope() {
+        let mut poison = Poison::poisoned(vec![1, 2]);
+        assert_eq!(poison.heal_scope(|data| { data.clear(); data.len() }), 0);
+        assert!(!poison.is_poisoned());
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            poison.heal_scope(|_| panic!())
+        })).unwrap_err();
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mut poison = Poison::new(());
+        let state = poison.debug_state();
+        assert!(!state.poisoned && !state.degraded && !state.frozen);
+
+        let _ = poison.scope_capturing(|_| panic!("broken"));
+        poison.freeze();
+
+        let state = poison.debug_state();
+        assert!(state.poisoned && state.frozen);
+        #[cfg(feature = "reason")]
+        assert_eq!(state.reason.as_deref(), Some("broken"));
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut poison = Poison::new(());
+        poison.freeze();
+        assert!(poison.is_frozen());
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+        assert!(!poison.is_poisoned());
+
+        poison.thaw();
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+        assert!(poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_try_map_result() {
+        let parse = |s: &str| s.parse::<i32>();
+
+        assert_eq!(try_map_result(Ok("1"), parse).unwrap().unwrap(), 1);
+        assert!(try_map_result(Ok("a"), parse).is_err());
+
+        let poisoned = try_map_result(Poison::poisoned("1").into_inner(), parse).unwrap();
+        assert_eq!(poisoned.unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let mut poison = Poison::new(1);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.lock_mode(PoisonMode::OnDropUnlessCommit).unwrap();
+            *guard.get_mut() += 1;
+
+            let read = guard.downgrade();
+            assert_eq!(*read.get(), 2);
+            panic!();
+        })).unwrap_err();
+
+        assert_eq!(*poison.get().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut poison = Poison::new(1);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let read = poison.lock().unwrap().downgrade();
+            let mut guard = read.upgrade();
+            *guard.get_mut() += 1;
+            panic!();
+        })).unwrap_err();
+
+        assert_eq!(*poison.get().unwrap_err().into_inner(), 2);
+    }
+
+    #[test]
+    fn test_checked_get() {
+        fn read(poison: &Poison<i32>) -> Result<i32, Box<dyn Error>> {
+            Ok(*poison.checked_get()?)
+        }
+
+        assert_eq!(read(&Poison::new(1)).unwrap(), 1);
+        assert_eq!(read(&Poison::poisoned(1)).unwrap_err().to_string(), "poisoned");
+        assert_eq!(Poison::poisoned(1).checked_get(), Err(Poisoned));
+    }
+
+    #[test]
+    fn test_poisoned_from() {
+        fn read(poison: &Poison<i32>) -> Result<i32, Poisoned> {
+            Ok(*poison.get()?)
+        }
+
+        fn write(poison: &mut Poison<i32>) -> Result<(), Poisoned> {
+            *poison.lock()?.get_mut() += 1;
+            Ok(())
+        }
+
+        let mut poison = Poison::new(1);
+        write(&mut poison).unwrap();
+        assert_eq!(read(&poison), Ok(2));
+
+        let mut poison = Poison::poisoned(1);
+        assert_eq!(write(&mut poison), Err(Poisoned));
+        assert_eq!(read(&poison), Err(Poisoned));
+    }
+
+    #[test]
+    fn test_get_or_poisoned() {
+        fn read(poison: &Poison<i32>) -> Result<i32, Poisoned> {
+            Ok(*poison.get_or_poisoned()? + 1)
+        }
+
+        let mut poison = Poison::new(1);
+        assert_eq!(poison.get_or_poisoned(), Ok(&1));
+        assert_eq!(read(&poison), Ok(2));
+
+        poison.raw.poison();
+        assert_eq!(poison.get_or_poisoned(), Err(Poisoned));
+        assert_eq!(read(&poison), Err(Poisoned));
+
+        poison.heal();
+        assert_eq!(read(&poison), Ok(2));
+    }
+
+    #[test]
+    fn test_with_then() {
+        let mut poison = Poison::new(vec![1]);
+        assert_eq!(poison.with_then(|data| data.push(2), |data| data.len()).unwrap(), 2);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = poison.with_then(|data| data.push(3), |_| -> () { panic!() });
+        })).unwrap_err();
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut poison = Poison::new(vec![]);
+        poison.batch(|batch| {
+            batch.step(|data| data.push(1));
+            assert_eq!(batch.step(|data| data.len()), 1);
+        }).unwrap();
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = poison.batch(|batch| {
+                batch.step(|data| data.push(2));
+                batch.step(|_| panic!());
+                batch.step(|data| data.push(3));
+            });
+        })).unwrap_err();
+
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn test_arm_scope() {
+        fn work(_: &mut i32) { panic!() }
+
+        let mut poison = Poison::new(1);
+        poison.freeze();
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut scope = poison.arm_scope();
+            work(scope.get_mut());
+        })).unwrap_err();
+
+        assert!(poison.is_poisoned());
+        assert!(poison.is_frozen());
+
+        poison.heal();
+        drop(poison.arm_scope());
+        assert!(!poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_compare_and_set() {
+        let mut poison = Poison::new(1);
+        assert!(poison.compare_and_set(&1, 2).unwrap());
+        assert!(!poison.compare_and_set(&1, 3).unwrap());
+        assert_eq!(*poison.get().unwrap(), 2);
+
+        let mut poison = Poison::poisoned(1);
+        assert!(!poison.compare_and_set(&1, 2).unwrap_err().into_inner());
+        assert_eq!(*poison.get().unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_split_at_guard() {
+        let mut poison = Poison::new(vec![1, 2, 3, 4]);
+
+        {
+            let mut guard = poison.split_at_guard(1).unwrap();
+            let (left, right) = guard.get_mut();
+            left[0] = 0;
+            right[0] = 0;
+        }
+        assert_eq!(*poison.get().unwrap(), [0, 0, 3, 4]);
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = poison.split_at_guard(2).unwrap();
+            let (_, right) = guard.get_mut();
+            right[0] = 5;
+            panic!();
+        })).unwrap_err();
+
+        assert_eq!(*poison.get().unwrap_err().into_inner(), [0, 0, 5, 4]);
+    }
+
+    #[test]
+    fn test_unwind_safe() {
+        use std::panic::{RefUnwindSafe, UnwindSafe};
+
+        fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+        assert_unwind_safe::<RawPoison>();
+        assert_unwind_safe::<Poison<i32>>();
+        assert_unwind_safe::<Poison<Vec<String>>>();
+    }
+
+    #[test]
+    fn test_guard_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<::PoisonGuard<i32>>();
+        assert_send_sync::<::PoisonGuard<[String]>>();
+        assert_send_sync::<::RawPoisonGuard>();
+
+        let mut poison = Poison::new(1);
+        let mut guard = poison.lock().unwrap();
+        guard.defer(|data| *data += 1);
+        thread::scope(|scope| { scope.spawn(move || *guard.get_mut() += 1); });
+        assert_eq!(*poison.get().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_on_heal() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let heals = Arc::new(AtomicUsize::new(0));
+        let counter = heals.clone();
+
+        let mut poison = Poison::poisoned(());
+        poison.set_on_heal(move || { counter.fetch_add(1, Ordering::SeqCst); });
+
+        poison.heal();
+        poison.heal();
+        assert_eq!(heals.load(Ordering::SeqCst), 1);
+
+        fn assert_sync<T: Sync>(_: &T) {}
+        assert_sync(&poison);
+
+        let shared = &poison;
+        assert!(panic::catch_unwind(|| shared.is_poisoned()).is_ok());
+    }
+
+    #[test]
+    fn test_try_get_mut() {
+        let mut poison = Poison::new(1);
+        assert_eq!(poison.try_get_mut(|data| { *data += 1; Ok::<_, ()>(*data) }), Ok(2));
+        assert!(!poison.is_poisoned());
+
+        assert_eq!(poison.try_get_mut(|_| Err::<(), _>("failed")), Err("failed"));
+        assert!(poison.is_poisoned());
+
+        poison.heal();
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = poison.try_get_mut(|_| -> Result<(), ()> { panic!() });
+        })).unwrap_err();
+        assert!(!poison.is_poisoned());
+    }
+
+    #[test]
+    fn test_map_into() {
+        let poison: Poison<String> = Poison::new("a").map_into();
+        assert_eq!(poison.into_inner().unwrap(), "a");
+
+        let poison: Poison<i64> = Poison::poisoned(1i32).map_into();
+        assert_eq!(poison.into_inner().unwrap_err().into_inner(), 1);
+    }
+
+    #[test]
+    fn test_lock_while() {
+        let mut poison = Poison::new(vec![1]);
+        assert!(poison.lock_while(|data| data.is_empty()).is_none());
+        poison.lock_while(|data| !data.is_empty()).unwrap().unwrap().get_mut().clear();
+        assert!(poison.lock_while(|data| !data.is_empty()).is_none());
+    }
+
+    #[cfg(feature = "audit")]
+    #[test]
+    fn test_history() {
+        use {PoisonTransition, AUDIT_CAPACITY};
+
+        let mut poison = Poison::new(());
+        assert!(poison.history().is_empty());
+
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = poison.lock().unwrap();
+            panic!();
+        })).unwrap_err();
+        poison.heal();
+
+        match *poison.history() {
+            [PoisonTransition::Poisoned(poisoned), PoisonTransition::Healed(healed)] => {
+                assert!(poisoned <= healed)
+            },
+            ref history => panic!("unexpected history {:?}", history)
+        }
+
+        for _ in 0..AUDIT_CAPACITY {
+            drop(poison.lock_mode(PoisonMode::OnDropUnlessCommit));
+            poison.heal();
+        }
+        assert_eq!(poison.history().len(), AUDIT_CAPACITY);
+        assert!(poison.history().windows(2).all(|w| w[0].at() <= w[1].at()));
+    }
+
+    #[test]
+    fn test_lock_deadline() {
+        let mut poison = Poison::new(());
+
+        {
+            let guard = poison.lock_deadline(Instant::now() + Duration::from_secs(3600)).unwrap();
+            assert!(guard.time_left().unwrap() > Duration::from_secs(0));
+        }
+        assert!(!poison.is_poisoned());
+
+        {
+            let guard = poison.lock_deadline(Instant::now()).unwrap();
+            thread::sleep(Duration::from_millis(1));
+            assert_eq!(guard.time_left(), Some(Duration::from_secs(0)));
+        }
+        assert!(poison.is_poisoned());
+
+        poison.heal();
+        assert_eq!(poison.lock().unwrap().time_left(), None);
+    }
+}
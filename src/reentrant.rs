@@ -0,0 +1,123 @@
+//! Reentrant poisoning, for recursive access on a single thread.
+
+use std::cell::Cell;
+use std::sync::{LockResult, PoisonError};
+use std::thread;
+
+/// A poisoning wrapper which can be locked several times at once.
+///
+/// Unlike Poison, locking takes a shared reference, so guards can be nested
+/// by recursive code. Only the outermost guard poisons on panic, so a single
+/// panic unwinding through several nested guards poisons exactly once.
+///
+/// Since nested guards alias, they only give shared access to the data, which
+/// should use interior mutability if it needs to be mutated. ReentrantPoison
+/// is not Sync, so all of its guards live on the thread which owns it.
+#[derive(Debug)]
+pub struct ReentrantPoison<T: ?Sized> {
+    poisoned: Cell<bool>,
+    depth: Cell<usize>,
+    data: T
+}
+
+/// A guard on a ReentrantPoison.
+///
+/// If this is the outermost guard and the current thread panics before it is
+/// dropped, the ReentrantPoison will become poisoned when it drops.
+#[derive(Debug)]
+pub struct ReentrantPoisonGuard<'poison, T: ?Sized + 'poison> {
+    poison: &'poison ReentrantPoison<T>,
+    panicking: bool
+}
+
+impl<T> ReentrantPoison<T> {
+    /// Create a new ReentrantPoison in a non-poisoned state.
+    #[inline]
+    pub fn new(data: T) -> ReentrantPoison<T> {
+        ReentrantPoison { poisoned: Cell::new(false), depth: Cell::new(0), data }
+    }
+
+    /// Extract the data from the ReentrantPoison.
+    ///
+    /// Returns PoisonError if the ReentrantPoison is poisoned.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        if self.poisoned.get() { Err(PoisonError::new(self.data)) } else { Ok(self.data) }
+    }
+}
+
+impl<T: ?Sized> ReentrantPoison<T> {
+    /// Get a reentrant poison lock on this ReentrantPoison.
+    ///
+    /// May be called while other guards on this ReentrantPoison are live.
+    ///
+    /// Returns PoisonError if the ReentrantPoison is poisoned.
+    #[inline]
+    pub fn lock(&self) -> LockResult<ReentrantPoisonGuard<'_, T>> {
+        self.depth.set(self.depth.get() + 1);
+
+        let guard = ReentrantPoisonGuard { poison: self, panicking: thread::panicking() };
+        if self.poisoned.get() { Err(PoisonError::new(guard)) } else { Ok(guard) }
+    }
+
+    /// Check if the ReentrantPoison is poisoned.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.get()
+    }
+
+    /// Heal the ReentrantPoison, unpoisoning it if it is poisoned.
+    #[inline]
+    pub fn heal(&mut self) {
+        self.poisoned.set(false);
+    }
+
+    /// Get the number of guards currently live on this ReentrantPoison.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth.get()
+    }
+}
+
+impl<'poison, T: ?Sized> ReentrantPoisonGuard<'poison, T> {
+    /// Get an immutable reference to the data.
+    pub fn get(&self) -> &T { &self.poison.data }
+}
+
+impl<'poison, T: ?Sized> Drop for ReentrantPoisonGuard<'poison, T> {
+    #[inline]
+    fn drop(&mut self) {
+        let depth = self.poison.depth.get() - 1;
+        self.poison.depth.set(depth);
+
+        if depth == 0 && !self.panicking && thread::panicking() {
+            self.poison.poisoned.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::panic::{self, AssertUnwindSafe};
+
+    use ReentrantPoison;
+
+    #[test]
+    fn test_reentrant_poison() {
+        fn fill(poison: &ReentrantPoison<RefCell<Vec<usize>>>, n: usize) {
+            let guard = poison.lock().unwrap();
+            assert_eq!(poison.depth(), n + 1);
+
+            guard.get().borrow_mut().push(n);
+            if n < 3 { fill(poison, n + 1) } else { panic!() }
+        }
+
+        let poison = ReentrantPoison::new(RefCell::new(Vec::new()));
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| fill(&poison, 0)));
+
+        assert_eq!(poison.depth(), 0);
+        assert!(poison.is_poisoned());
+        assert_eq!(poison.into_inner().unwrap_err().into_inner().into_inner(), [0, 1, 2, 3]);
+    }
+}
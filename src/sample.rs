@@ -0,0 +1,42 @@
+//! Thread-local random sampling, for sampled invariant checks and
+//! probabilistic healing.
+//!
+//! Each thread has its own xorshift generator, seeded randomly the first time
+//! it is used. It is fast rather than high quality, which is plenty for
+//! deciding whether to run a check. Tests can reseed it to make sampling
+//! deterministic.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+thread_local! {
+    // The generator state for this thread, which is never zero.
+    static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u8) | 1);
+}
+
+/// Decide whether to sample, returning true with probability `rate`.
+///
+/// A rate of at least 1 always samples, and a rate of at most 0 never does,
+/// without consulting the generator.
+#[inline]
+pub fn sample(rate: f64) -> bool {
+    if rate >= 1.0 { return true }
+    if rate.is_nan() || rate <= 0.0 { return false }
+
+    STATE.try_with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }).unwrap_or(false)
+}
+
+/// Reseed the generator for this thread.
+#[cfg(any(test, feature = "test-util"))]
+pub fn seed(seed: u64) {
+    let _ = STATE.try_with(|state| state.set(seed | 1));
+}